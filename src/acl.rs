@@ -0,0 +1,404 @@
+use crate::auth::{self, AuthInfo, Identity};
+use crate::config::{AclRule, Perm};
+use crate::path_res;
+use crate::share::ShareGrant;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use anyhow::{Result, anyhow};
+use glob::{MatchOptions, Pattern};
+use std::collections::HashMap;
+use std::path::Path;
+
+enum PathMatcher {
+    /// Matches the path itself and everything under it.
+    Prefix(String),
+    Glob(Pattern),
+}
+
+impl PathMatcher {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Prefix(prefix) => {
+                prefix == "/"
+                    || path == prefix
+                    || path
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }
+            PathMatcher::Glob(pattern) => pattern.matches_with(
+                path,
+                MatchOptions {
+                    require_literal_separator: true,
+                    ..MatchOptions::new()
+                },
+            ),
+        }
+    }
+}
+
+struct Rule {
+    path: PathMatcher,
+    users: Vec<String>,
+    perms: Vec<Perm>,
+}
+
+/// Access control rules deciding who may do what under which paths. The first rule whose path
+/// matches applies. Paths no rule matches are readable and listable by signed in users, or by
+/// everyone when authentication is disabled.
+#[derive(Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    groups: HashMap<String, Vec<String>>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>, groups: HashMap<String, Vec<String>>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                for user in &rule.users {
+                    if let Some(group) = user.strip_prefix('@')
+                        && !groups.contains_key(group)
+                    {
+                        return Err(anyhow!(
+                            "ACL rule [{}]: unknown group [{}]",
+                            rule.path,
+                            user
+                        ));
+                    }
+                }
+                let path = if rule.path.contains(['*', '?', '[']) {
+                    let pattern = Pattern::new(&rule.path)
+                        .map_err(|err| anyhow!("ACL rule [{}]: {}", rule.path, err))?;
+                    PathMatcher::Glob(pattern)
+                } else {
+                    let prefix = path_res::normalize(&rule.path)
+                        .map_err(|err| anyhow!("ACL rule [{}]: {:?}", rule.path, err))?;
+                    PathMatcher::Prefix(prefix)
+                };
+                Ok(Rule {
+                    path,
+                    users: rule.users,
+                    perms: rule.perms,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { rules, groups })
+    }
+
+    fn user_matches(&self, principal: &str, user: Option<&str>) -> bool {
+        match (principal, user) {
+            ("anonymous", _) => true,
+            ("*", user) => user.is_some(),
+            (_, None) => false,
+            (principal, Some(user)) => match principal.strip_prefix('@') {
+                Some(group) => self
+                    .groups
+                    .get(group)
+                    .is_some_and(|members| members.iter().any(|member| member == user)),
+                None => principal == user,
+            },
+        }
+    }
+
+    /// Whether `user` (`None` for anonymous requests) has `perm` on the normalized `path`.
+    pub fn allows(&self, path: &str, user: Option<&str>, perm: Perm, auth_enabled: bool) -> bool {
+        match self.rules.iter().find(|rule| rule.path.matches(path)) {
+            Some(rule) => {
+                rule.perms.contains(&perm)
+                    && rule
+                        .users
+                        .iter()
+                        .any(|principal| self.user_matches(principal, user))
+            }
+            None => perm != Perm::Write && (user.is_some() || !auth_enabled),
+        }
+    }
+}
+
+/// Whether the requester has any of `perms` on the normalized `path`, through the rules or a
+/// share link.
+pub fn permits(req: &HttpRequest, acl: &Acl, path: &str, perms: &[Perm]) -> bool {
+    let auth_enabled = req.app_data::<AuthInfo>().is_some();
+    let extensions = req.extensions();
+    let user = extensions.get::<Identity>().map(|id| id.user.as_str());
+    if perms
+        .iter()
+        .any(|perm| acl.allows(path, user, *perm, auth_enabled))
+    {
+        return true;
+    }
+    // a share link grants reading and listing under the shared path
+    extensions
+        .get::<ShareGrant>()
+        .is_some_and(|grant| grant.covers(path))
+        && perms.iter().any(|perm| *perm != Perm::Write)
+}
+
+/// Checks that the requester has any of `perms` on `rel_path`, a decoded path relative to the
+/// contents root, through the rules or a share link. Anonymous requesters are asked to
+/// authenticate, others get 403.
+pub fn authorize(
+    req: &HttpRequest,
+    acl: &Acl,
+    rel_path: &str,
+    perms: &[Perm],
+) -> Result<(), HttpResponse> {
+    let path = path_res::normalize(rel_path).map_err(|err| err.to_response())?;
+    if permits(req, acl, &path, perms) {
+        return Ok(());
+    }
+    let anonymous = req.extensions().get::<Identity>().is_none();
+    if anonymous && req.app_data::<AuthInfo>().is_some() {
+        Err(auth::challenge(req))
+    } else {
+        Err(HttpResponse::Forbidden().body("Access denied"))
+    }
+}
+
+/// Like [`authorize`], and checks the path `resolved` from `rel_path` leads to as well when
+/// symlinks make it another path inside the root, so that a link under a public path does not
+/// expose a protected one.
+pub async fn authorize_resolved(
+    req: &HttpRequest,
+    acl: &Acl,
+    root: &Path,
+    resolved: &Path,
+    rel_path: &str,
+    perms: &[Perm],
+) -> Result<(), HttpResponse> {
+    authorize(req, acl, rel_path, perms)?;
+    match path_res::root_relative(root, resolved).await {
+        Some(target) => authorize(req, acl, &target, perms),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::{AppState, app_config};
+    use ::base64::Engine;
+    use actix_http::StatusCode;
+    use actix_web::http::header::WWW_AUTHENTICATE;
+    use actix_web::test::{TestRequest, call_and_read_body, call_service, init_service};
+    use actix_web::{App, middleware::from_fn, web};
+    use base64::engine::general_purpose as base64;
+    use std::path::PathBuf;
+
+    fn rule(path: &str, users: &[&str], perms: &[Perm]) -> AclRule {
+        AclRule {
+            path: String::from(path),
+            users: users.iter().map(|user| user.to_string()).collect(),
+            perms: perms.to_vec(),
+        }
+    }
+
+    fn test_acl() -> Acl {
+        Acl::new(
+            vec![
+                rule("/res_dir/images", &["anonymous"], &[Perm::Read, Perm::List]),
+                rule("/**/*.epub", &["alice"], &[Perm::Read]),
+                rule("/src/", &["@team"], &[Perm::Read, Perm::List]),
+            ],
+            HashMap::from([(String::from("team"), vec![String::from("bob")])]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_acl_allows() {
+        let acl = test_acl();
+        for (path, user, perm, allowed) in [
+            ("/res_dir/images", None, Perm::List, true),
+            ("/res_dir/images/a.png", None, Perm::Read, true),
+            ("/res_dir/images/a.png", None, Perm::Write, false),
+            ("/res_dir/images2", None, Perm::List, false),
+            ("/res_dir/images2", Some("bob"), Perm::List, true),
+            ("/res_dir/v2.epub", Some("alice"), Perm::Read, true),
+            ("/res_dir/v2.epub", Some("bob"), Perm::Read, false),
+            ("/v2.epub", Some("alice"), Perm::Read, true),
+            ("/src/lib.rs", Some("bob"), Perm::Read, true),
+            ("/src", Some("bob"), Perm::List, true),
+            ("/src/lib.rs", Some("alice"), Perm::Read, false),
+            ("/", Some("alice"), Perm::Write, false),
+            ("/", None, Perm::Read, false),
+        ] {
+            assert_eq!(
+                acl.allows(path, user, perm, true),
+                allowed,
+                "{} {:?} {:?}",
+                path,
+                user,
+                perm
+            );
+        }
+        assert!(acl.allows("/", None, Perm::List, false));
+
+        let unknown_group = vec![rule("/", &["@nobody"], &[Perm::Read])];
+        assert!(Acl::new(unknown_group, HashMap::new()).is_err());
+        let bad_glob = vec![rule("/[", &["*"], &[Perm::Read])];
+        assert!(Acl::new(bad_glob, HashMap::new()).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_acl_enforced() {
+        let users = HashMap::from([
+            (String::from("alice"), bcrypt::hash("alicepass", 4).unwrap()),
+            (String::from("bob"), bcrypt::hash("bobpass", 4).unwrap()),
+        ]);
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.acl = test_acl();
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(web::Data::new(app_state))
+                .app_data(AuthInfo::new(users))
+                .wrap(from_fn(auth::authenticate)),
+        )
+        .await;
+        let epub_cont = format!(
+            "/epub_cont/{}/OEBPS/valentinhauy11.html",
+            base64::URL_SAFE_NO_PAD.encode("res_dir/v2.epub")
+        );
+        for (uri, cred, status) in [
+            ("/res_dir/images/", None, StatusCode::OK),
+            ("/res_dir/images/dir_list.png", None, StatusCode::OK),
+            ("/", None, StatusCode::UNAUTHORIZED),
+            ("/epub_toc/res_dir/v2.epub", None, StatusCode::UNAUTHORIZED),
+            (
+                "/epub_toc/res_dir/v2.epub",
+                Some("bob:bobpass"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/epub_toc/res_dir/v2.epub",
+                Some("alice:alicepass"),
+                StatusCode::OK,
+            ),
+            (&epub_cont, Some("bob:bobpass"), StatusCode::FORBIDDEN),
+            (&epub_cont, Some("alice:alicepass"), StatusCode::OK),
+            ("/res_dir/v2.epub", Some("alice:alicepass"), StatusCode::OK),
+            (
+                "/res_dir/v2.epub",
+                Some("bob:bobpass"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "/src/lib.rs",
+                Some("alice:alicepass"),
+                StatusCode::FORBIDDEN,
+            ),
+            ("/src/lib.rs", Some("bob:bobpass"), StatusCode::OK),
+            ("/src/", Some("bob:bobpass"), StatusCode::OK),
+            (
+                "/src/non_exist",
+                Some("alice:alicepass"),
+                StatusCode::FORBIDDEN,
+            ),
+            ("/Cargo.toml", Some("alice:wrong"), StatusCode::UNAUTHORIZED),
+            ("/Cargo.toml", Some("alice:alicepass"), StatusCode::OK),
+        ] {
+            let mut req = TestRequest::default().uri(uri);
+            if let Some(cred) = cred {
+                req = req.append_header((
+                    "Authorization",
+                    format!("Basic {}", base64::STANDARD.encode(cred)),
+                ));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "{} {:?}", uri, cred);
+            if status == StatusCode::UNAUTHORIZED {
+                assert!(resp.headers().contains_key(WWW_AUTHENTICATE));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_acl_symlinks() {
+        let root = TempDir::new("acl");
+        std::fs::create_dir_all(root.join("public")).unwrap();
+        std::fs::create_dir_all(root.join("private")).unwrap();
+        std::fs::write(root.join("private/secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink("../private/secret.txt", root.join("public/link.txt")).unwrap();
+        std::os::unix::fs::symlink("../private", root.join("public/dir_link")).unwrap();
+        let outside = TempDir::new("acl_outside");
+        std::fs::write(outside.join("outside.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(
+            outside.join("outside.txt"),
+            root.join("public/out_link.txt"),
+        )
+        .unwrap();
+
+        let users = HashMap::from([
+            (String::from("alice"), bcrypt::hash("alicepass", 4).unwrap()),
+            (String::from("bob"), bcrypt::hash("bobpass", 4).unwrap()),
+        ]);
+        let mut app_state = AppState::new(root.to_path_buf());
+        app_state.symlink_policy = crate::config::SymlinkPolicy::FollowWithinRoot;
+        app_state.acl = Acl::new(
+            vec![
+                rule("/public", &["anonymous"], &[Perm::Read, Perm::List]),
+                rule("/private", &["alice"], &[Perm::Read, Perm::List]),
+            ],
+            HashMap::new(),
+        )
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(web::Data::new(app_state))
+                .app_data(AuthInfo::new(users))
+                .wrap(from_fn(auth::authenticate)),
+        )
+        .await;
+        let get = |uri: &str, cred: Option<&str>| {
+            let mut req = TestRequest::default().uri(uri);
+            if let Some(cred) = cred {
+                req = req.append_header((
+                    "Authorization",
+                    format!("Basic {}", base64::STANDARD.encode(cred)),
+                ));
+            }
+            req.to_request()
+        };
+
+        // the rules of the path a link leads to apply as well
+        for (uri, cred, status) in [
+            ("/public/link.txt", None, StatusCode::UNAUTHORIZED),
+            (
+                "/public/link.txt",
+                Some("bob:bobpass"),
+                StatusCode::FORBIDDEN,
+            ),
+            ("/public/link.txt", Some("alice:alicepass"), StatusCode::OK),
+            (
+                "/public/dir_link/",
+                Some("bob:bobpass"),
+                StatusCode::FORBIDDEN,
+            ),
+            ("/public/dir_link/", Some("alice:alicepass"), StatusCode::OK),
+        ] {
+            let resp = call_service(&app, get(uri, cred)).await;
+            assert_eq!(resp.status(), status, "{} {:?}", uri, cred);
+        }
+
+        // listings only show what the requester may access
+        let body = call_and_read_body(&app, get("/", Some("bob:bobpass"))).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("public"));
+        assert!(!body.contains("private"));
+        let body = call_and_read_body(&app, get("/", Some("alice:alicepass"))).await;
+        assert!(String::from_utf8_lossy(&body).contains("private"));
+        // nor links to what they may not access, or that the symlink policy does not follow
+        let body = call_and_read_body(&app, get("/public/", Some("bob:bobpass"))).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains("link.txt"));
+        assert!(!body.contains("dir_link"));
+        let body = call_and_read_body(&app, get("/public/", Some("alice:alicepass"))).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(r#"<a href="/public/link.txt">link.txt</a>"#));
+        assert!(body.contains("dir_link"));
+        assert!(!body.contains("out_link.txt"));
+    }
+}
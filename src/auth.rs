@@ -0,0 +1,810 @@
+use crate::AppState;
+use crate::config::{self, HashScheme, UserAction};
+use crate::session::{self, SessionStore};
+use crate::share;
+use crate::throttle::{AuthLimits, Throttle, log_event};
+use crate::tls::{self, ClientCert, UnknownCertUser};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::cookie::Cookie;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::http::header::{AUTHORIZATION, Accept, LOCATION, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError, mime, web};
+use actix_web_httpauth::extractors::{AuthenticationError, basic::BasicAuth};
+use anyhow::{Result, anyhow};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, password_hash::rand_core::OsRng};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+pub fn hash_password(password: &str, scheme: HashScheme) -> Result<String> {
+    match scheme {
+        HashScheme::Bcrypt => Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?),
+        HashScheme::Argon2 => {
+            let salt = SaltString::generate(&mut OsRng);
+            Ok(Argon2::default()
+                .hash_password(password.as_bytes(), &salt)?
+                .to_string())
+        }
+    }
+}
+
+/// Checks the password against a bcrypt or argon2 (PHC string) hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Checks that `hash` is a well-formed bcrypt hash or argon2 PHC string.
+pub fn check_hash(hash: &str) -> Result<()> {
+    if hash.starts_with("$argon2") {
+        let parsed =
+            PasswordHash::new(hash).map_err(|err| anyhow!("Invalid argon2 hash: {}", err))?;
+        argon2::Algorithm::try_from(parsed.algorithm)
+            .and_then(|_| argon2::Params::try_from(&parsed))
+            .map_err(|err| anyhow!("Invalid argon2 hash: {}", err))?;
+        if parsed.salt.is_none() || parsed.hash.is_none() {
+            return Err(anyhow!("Invalid argon2 hash: missing salt or hash"));
+        }
+    } else {
+        let parts = hash
+            .parse::<bcrypt::HashParts>()
+            .map_err(|err| anyhow!("Invalid bcrypt hash: {}", err))?;
+        if !(4..=31).contains(&parts.get_cost()) {
+            return Err(anyhow!(
+                "Invalid bcrypt hash: cost {} is not in 4..=31",
+                parts.get_cost()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_user_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(':') || name.chars().any(char::is_whitespace) {
+        return Err(anyhow!("Invalid user name [{}]", name));
+    }
+    Ok(())
+}
+
+/// Parses an htpasswd-style users file: one `name:hash` per line, empty lines and lines starting
+/// with `#` are ignored.
+pub fn parse_users_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut users = Vec::new();
+    for (num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, hash)) = line.split_once(':') else {
+            return Err(anyhow!("Line {}: expected `name:hash`", num + 1));
+        };
+        check_user_name(name).map_err(|err| anyhow!("Line {}: {}", num + 1, err))?;
+        users.push((String::from(name), String::from(hash)));
+    }
+    Ok(users)
+}
+
+/// Collects the users from `user_name`/`password_hash`, the `[[users]]` table and `users_file`.
+/// A user name may only be defined once.
+pub fn load_users(config: &config::Config) -> Result<HashMap<String, String>> {
+    let mut entries = Vec::new();
+    if let (Some(name), Some(hash)) = (&config.user_name, &config.password_hash) {
+        entries.push((name.clone(), hash.clone()));
+    }
+    for user in &config.users {
+        check_user_name(&user.name)?;
+        entries.push((user.name.clone(), user.password_hash.clone()));
+    }
+    if let Some(path) = &config.users_file {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("Reading users file {:?} failed: {}", path, err))?;
+        let file_users = parse_users_file(&content)
+            .map_err(|err| anyhow!("Invalid users file {:?}: {}", path, err))?;
+        entries.extend(file_users);
+    }
+
+    let mut users = HashMap::new();
+    for (name, hash) in entries {
+        if users.contains_key(&name) {
+            return Err(anyhow!("Duplicated user [{}]", name));
+        }
+        users.insert(name, hash);
+    }
+    Ok(users)
+}
+
+fn read_password(password: Option<String>) -> Result<String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            eprint!("Password: ");
+            std::io::stderr().flush()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            String::from(line.trim_end_matches(['\r', '\n']))
+        }
+    };
+    if password.is_empty() {
+        return Err(anyhow!("Empty password"));
+    }
+    Ok(password)
+}
+
+/// Adds, removes or rehashes a user in the users file. Other lines, comments included, are kept.
+pub fn run_user_command(file: &Path, action: UserAction) -> Result<()> {
+    let content = match std::fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = content.lines().map(String::from).collect::<Vec<_>>();
+    let name = match &action {
+        UserAction::Add { name, .. }
+        | UserAction::Remove { name }
+        | UserAction::Rehash { name, .. } => name.clone(),
+    };
+    check_user_name(&name)?;
+    let index = lines
+        .iter()
+        .position(|line| line.trim().split_once(':').map(|(n, _)| n) == Some(name.as_str()));
+
+    let not_found = || anyhow!("User [{}] not found", name);
+    match action {
+        UserAction::Add {
+            password, scheme, ..
+        } => {
+            if index.is_some() {
+                return Err(anyhow!("User [{}] already exists", name));
+            }
+            let hash = hash_password(&read_password(password)?, scheme)?;
+            lines.push(format!("{}:{}", name, hash));
+        }
+        UserAction::Remove { .. } => {
+            lines.remove(index.ok_or_else(not_found)?);
+        }
+        UserAction::Rehash {
+            password, scheme, ..
+        } => {
+            let index = index.ok_or_else(not_found)?;
+            let hash = hash_password(&read_password(password)?, scheme)?;
+            lines[index] = format!("{}:{}", name, hash);
+        }
+    }
+
+    let mut content = lines.join("\n");
+    content.push('\n');
+    replace_file(file, &content)
+}
+
+/// Replaces the content of `file` through `<file>.tmp`, so that readers see either the old or
+/// the new content. The permissions and the owner of an existing file are kept.
+fn replace_file(file: &Path, content: &str) -> Result<()> {
+    let mut tmp_path = file.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let write = || -> std::io::Result<()> {
+        let old_meta = match std::fs::metadata(file) {
+            Ok(meta) => Some(meta),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let mut tmp_file = std::fs::File::create(&tmp_path)?;
+        if let Some(meta) = old_meta {
+            tmp_file.set_permissions(meta.permissions())?;
+            std::os::unix::fs::fchown(&tmp_file, Some(meta.uid()), Some(meta.gid()))?;
+        }
+        tmp_file.write_all(content.as_bytes())?;
+        std::fs::rename(&tmp_path, file)
+    };
+    write().map_err(|err| {
+        let _ = std::fs::remove_file(&tmp_path);
+        anyhow!("Cannot write [{}]: {}", file.display(), err)
+    })
+}
+
+/// Digest of a password verified against a stored hash. Including the hash means the digest no
+/// longer matches once the user's hash changes.
+fn cred_digest(hash: &str, password: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(hash)
+        .chain_update([0])
+        .chain_update(password)
+        .finalize()
+        .into()
+}
+
+#[derive(Clone)]
+pub struct AuthInfo {
+    /// User name to password hash.
+    users: Arc<HashMap<String, String>>,
+    /// User name to the digest of the last verified password, so the slow hash verification
+    /// only runs once per user.
+    verified: Arc<Mutex<HashMap<String, [u8; 32]>>>,
+    /// Login sessions, only with form login enabled.
+    sessions: Option<Arc<SessionStore>>,
+    throttle: Arc<Throttle>,
+    /// Refuse anonymous requests, see [`config::ClientAuth::CertOrPassword`].
+    credentials_required: bool,
+}
+
+/// The outcome of checking a user's password.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Verified,
+    Denied,
+    /// The client or the user failed too often and has to wait this long.
+    LockedOut(Duration),
+}
+
+impl AuthInfo {
+    pub fn new(users: HashMap<String, String>) -> Self {
+        Self {
+            users: Arc::new(users),
+            verified: Arc::new(Mutex::new(HashMap::new())),
+            sessions: None,
+            throttle: Arc::new(Throttle::new(AuthLimits::default())),
+            credentials_required: false,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: AuthLimits) -> Self {
+        self.throttle = Arc::new(Throttle::new(limits));
+        self
+    }
+
+    /// Enables form login with sessions kept in `store`, which may be the store of the site a
+    /// reloaded config replaces.
+    pub fn with_sessions(mut self, store: Arc<SessionStore>) -> Self {
+        self.sessions = Some(store);
+        self
+    }
+
+    /// Refuses requests without a client certificate, a password or a session, whatever the
+    /// access control rules allow.
+    pub fn with_credentials_required(mut self) -> Self {
+        self.credentials_required = true;
+        self
+    }
+
+    pub fn sessions(&self) -> Option<&SessionStore> {
+        self.sessions.as_deref()
+    }
+
+    pub(crate) fn session_store(&self) -> Option<Arc<SessionStore>> {
+        self.sessions.clone()
+    }
+
+    /// Uses the failure counts of `throttle`, which may be the throttle of the site a reloaded
+    /// config replaces, with the limits set so far.
+    pub(crate) fn with_throttle_of(mut self, throttle: &Throttle) -> Self {
+        self.throttle = Arc::new(throttle.with_limits(self.throttle.limits()));
+        self
+    }
+
+    pub(crate) fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    pub(crate) fn users(&self) -> &HashMap<String, String> {
+        &self.users
+    }
+
+    pub(crate) fn user_hash(&self, user: &str) -> Option<&str> {
+        self.users.get(user).map(String::as_str)
+    }
+
+    /// Checks the password of `user` for a request from `ip`. Hashes are verified on the
+    /// blocking thread pool, at most `verify_concurrency` at a time, and locked out clients and
+    /// users are refused without verifying anything.
+    pub async fn verify(&self, user: &str, password: &str, ip: Option<IpAddr>) -> Verdict {
+        let hash = self.users.get(user);
+        // failures are only counted per user for existing users, so guessed names can not grow
+        // the counters without bound
+        let known_user = hash.map(|_| user);
+        if let Some(wait) = self.throttle.locked(ip, known_user) {
+            log_event("auth_locked", ip, user, "");
+            return Verdict::LockedOut(wait);
+        }
+
+        let verified = match hash {
+            None => false,
+            Some(hash) => {
+                let digest = cred_digest(hash, password);
+                let cached = self.verified.lock().await.get(user).copied();
+                match cached {
+                    Some(cached) => cached == digest,
+                    None => {
+                        let _permit = self.throttle.permit().await;
+                        let (password, hash) = (String::from(password), hash.clone());
+                        let res = web::block(move || verify_password(&password, &hash)).await;
+                        let verified = res.unwrap_or(false);
+                        if verified {
+                            self.verified
+                                .lock()
+                                .await
+                                .insert(String::from(user), digest);
+                        }
+                        verified
+                    }
+                }
+            }
+        };
+
+        if verified {
+            self.throttle.record_success(ip, user);
+            return Verdict::Verified;
+        }
+        log_event("auth_failure", ip, user, "");
+        if let Some(lockout) = self.throttle.record_failure(ip, known_user) {
+            log_event(
+                "auth_lockout",
+                ip,
+                user,
+                &format!(" seconds={}", lockout.as_secs()),
+            );
+        }
+        Verdict::Denied
+    }
+}
+
+/// The user a request has been authenticated as, stored in the request extensions.
+pub struct Identity {
+    pub user: String,
+    /// Whether the user signed in through the login form rather than basic auth.
+    pub session: bool,
+}
+
+/// The 401 response asking the client for basic auth credentials.
+fn basic_challenge() -> HttpResponse {
+    let config =
+        actix_web_httpauth::extractors::basic::Config::default().realm("My-Content-Server");
+    AuthenticationError::from(config).error_response()
+}
+
+/// The response asking an anonymous client to authenticate. With form login enabled, browsers
+/// navigating to a page are redirected to the login page, other clients get a basic auth
+/// challenge.
+pub fn challenge(req: &HttpRequest) -> HttpResponse {
+    let form_login = req
+        .app_data::<AuthInfo>()
+        .is_some_and(|info| info.sessions.is_some());
+    let wants_html = req
+        .get_header::<Accept>()
+        .is_some_and(|accept| accept.iter().any(|mime| mime.item == mime::TEXT_HTML));
+    if form_login && req.method() == Method::GET && wants_html {
+        let target = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        return HttpResponse::SeeOther()
+            .insert_header((
+                LOCATION,
+                format!("/login?next={}", urlencoding::encode(target)),
+            ))
+            .finish();
+    }
+    basic_challenge()
+}
+
+fn locked_out(wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, wait.as_secs().max(1)))
+        .body("Too many failed attempts")
+}
+
+/// The 401 response asking for the password of a share link, given as the basic auth password.
+fn share_challenge() -> HttpResponse {
+    let config = actix_web_httpauth::extractors::basic::Config::default().realm("Shared link");
+    AuthenticationError::from(config).error_response()
+}
+
+/// Checks the share token the request carries, if any, and records the [`ShareGrant`]. Returns
+/// the grant cookie to set when the token came from the link, and whether the basic auth
+/// credentials were the share password. Broken tokens in links are rejected, a broken cookie is
+/// ignored.
+async fn check_share(
+    req: &mut ServiceRequest,
+) -> Result<(Option<Cookie<'static>>, bool), HttpResponse> {
+    let Some(app_state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok((None, false));
+    };
+    let Some((token, from_link)) = share::request_token(req) else {
+        return Ok((None, false));
+    };
+    let mut grant = match app_state.shares.verify(&token) {
+        Ok(grant) => grant,
+        Err(err) if from_link => return Err(err.to_response()),
+        Err(_) => return Ok((None, false)),
+    };
+    let mut used_password = false;
+    if grant.is_locked() {
+        let ip = req.peer_addr().map(|addr| addr.ip());
+        let password = match req.headers().contains_key(AUTHORIZATION) {
+            true => req.extract::<BasicAuth>().await.ok(),
+            false => None,
+        };
+        let password = password
+            .as_ref()
+            .map(|cred| cred.password().unwrap_or_default());
+        if let Some(password) = password {
+            // failures are counted per link as well, so that clients without an address are
+            // limited too
+            let throttle = &app_state.share_throttle;
+            if let Some(wait) = throttle.locked(ip, Some(grant.id())) {
+                log_event("auth_locked", ip, "", " share");
+                return Err(locked_out(wait));
+            }
+            used_password = app_state.shares.unlock(&mut grant, password);
+            if !used_password {
+                log_event("auth_failure", ip, "", " share");
+                if let Some(lockout) = throttle.record_failure(ip, Some(grant.id())) {
+                    log_event(
+                        "auth_lockout",
+                        ip,
+                        "",
+                        &format!(" share seconds={}", lockout.as_secs()),
+                    );
+                }
+            }
+        }
+        if grant.is_locked() {
+            return match from_link {
+                true => Err(share_challenge()),
+                false => Ok((None, false)),
+            };
+        }
+    }
+    let cookie = from_link.then(|| {
+        let secure = tls::is_https(req.request());
+        app_state.shares.cookie(&token, &grant, secure)
+    });
+    req.extensions_mut().insert(grant);
+    Ok((cookie, used_password))
+}
+
+/// Checks the share link token, then verifies the basic auth credentials, the client
+/// certificate or the session cookie when the request has any, and records the [`Identity`].
+/// Requests without credentials pass through as anonymous, what they may access is decided by
+/// the access control rules.
+pub async fn authenticate<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    if req.conn_data::<UnknownCertUser>().is_some() {
+        let resp = HttpResponse::Forbidden().body("The client certificate names no user");
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    let credentials_required = req
+        .app_data::<AuthInfo>()
+        .is_some_and(|info| info.credentials_required);
+    let (share_cookie, used_password) = match credentials_required {
+        true => (None, false),
+        false => match check_share(&mut req).await {
+            Ok(res) => res,
+            Err(resp) => return Ok(req.into_response(resp).map_into_right_body()),
+        },
+    };
+    // when the credentials were the share password, they are not checked as a user's
+    if !used_password
+        && let Some(info) = req.app_data::<AuthInfo>().cloned()
+        && req.headers().contains_key(AUTHORIZATION)
+    {
+        let cred = req.extract::<BasicAuth>().await;
+        let ip = req.peer_addr().map(|addr| addr.ip());
+        let verdict = match &cred {
+            Ok(cred) => {
+                let password = cred.password().unwrap_or_default();
+                info.verify(cred.user_id(), password, ip).await
+            }
+            Err(_) => Verdict::Denied,
+        };
+        match verdict {
+            Verdict::Verified => {}
+            Verdict::Denied => {
+                return Ok(req.into_response(basic_challenge()).map_into_right_body());
+            }
+            Verdict::LockedOut(wait) => {
+                return Ok(req.into_response(locked_out(wait)).map_into_right_body());
+            }
+        }
+        let user = String::from(cred.unwrap().user_id());
+        req.extensions_mut().insert(Identity {
+            user,
+            session: false,
+        });
+    } else if let Some(cert) = req.conn_data::<ClientCert>() {
+        let user = cert.user.clone();
+        req.extensions_mut().insert(Identity {
+            user,
+            session: false,
+        });
+    } else if let Some(info) = req.app_data::<AuthInfo>()
+        && let Some(identity) = session::identify(&req, info)
+    {
+        req.extensions_mut().insert(identity);
+    }
+    if credentials_required
+        && req.extensions().get::<Identity>().is_none()
+        && req.path() != "/login"
+    {
+        let resp = challenge(req.request());
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    let mut res = next.call(req).await?;
+    if let Some(cookie) = share_cookie {
+        res.response_mut().add_cookie(&cookie)?;
+    }
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::{AppState, app_config};
+    use ::base64::Engine;
+    use actix_http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, middleware::from_fn, web};
+    use base64::engine::general_purpose as base64;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_hash_password() {
+        for scheme in [HashScheme::Bcrypt, HashScheme::Argon2] {
+            let hash = hash_password("secret", scheme).unwrap();
+            assert!(verify_password("secret", &hash));
+            assert!(!verify_password("Secret", &hash));
+        }
+        assert!(!verify_password("secret", "$argon2id$broken"));
+        for scheme in [HashScheme::Bcrypt, HashScheme::Argon2] {
+            assert!(check_hash(&hash_password("secret", scheme).unwrap()).is_ok());
+        }
+        for hash in [
+            "",
+            "secret",
+            "$2b$12$abc",
+            "$2b$99$RIHLGy9BQSOw5R.ZaMtMme9jfsHVo8vnXcjeRN9NOtPqRhJEJT8Bm",
+            "$argon2id$broken",
+            "$argon2xx$v=19$m=19456,t=2,p=1$TnalM7QkK9LKF0mkiLdKgA$mBFxzAT4ijhAvLCj9zhRDC3GFsLf27cqKgA9X6SPXXQ",
+        ] {
+            assert!(check_hash(hash).is_err(), "{}", hash);
+        }
+        assert!(!verify_password("secret", "not a hash"));
+    }
+
+    #[test]
+    fn test_parse_users_file() {
+        let users =
+            parse_users_file("# team\n\nalice:$2b$12$abc\n  bob:$argon2id$xyz  \n").unwrap();
+        assert_eq!(
+            users,
+            vec![
+                (String::from("alice"), String::from("$2b$12$abc")),
+                (String::from("bob"), String::from("$argon2id$xyz"))
+            ]
+        );
+        assert!(parse_users_file("alice").is_err());
+        assert!(parse_users_file(":hash").is_err());
+    }
+
+    #[test]
+    fn test_user_command() {
+        let dir = TempDir::new("users");
+        let file = dir.join("users");
+        std::fs::write(&file, "# team\n").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let add = |name: &str, password: &str| UserAction::Add {
+            name: String::from(name),
+            password: Some(String::from(password)),
+            scheme: HashScheme::Argon2,
+        };
+        run_user_command(&file, add("alice", "pass1")).unwrap();
+        run_user_command(&file, add("bob", "pass2")).unwrap();
+        assert!(run_user_command(&file, add("alice", "pass3")).is_err());
+        run_user_command(
+            &file,
+            UserAction::Rehash {
+                name: String::from("alice"),
+                password: Some(String::from("pass3")),
+                scheme: HashScheme::Bcrypt,
+            },
+        )
+        .unwrap();
+        run_user_command(
+            &file,
+            UserAction::Remove {
+                name: String::from("bob"),
+            },
+        )
+        .unwrap();
+        let remove_bob = UserAction::Remove {
+            name: String::from("bob"),
+        };
+        assert!(run_user_command(&file, remove_bob).is_err());
+
+        let content = std::fs::read_to_string(&file).unwrap();
+        assert!(content.starts_with("# team\n"));
+        let mode = std::fs::metadata(&file).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        let users = parse_users_file(&content).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].0, "alice");
+        assert!(verify_password("pass3", &users[0].1));
+    }
+
+    #[actix_web::test]
+    async fn test_multi_user_auth() {
+        let mut users = HashMap::new();
+        users.insert(
+            String::from("alice"),
+            hash_password("alicepass", HashScheme::Argon2).unwrap(),
+        );
+        users.insert(
+            String::from("bob"),
+            hash_password("bobpass", HashScheme::Bcrypt).unwrap(),
+        );
+        let auth_info = AuthInfo::new(users);
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(app_data)
+                .app_data(auth_info.clone())
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+        for (cred, status) in [
+            ("alice:alicepass", StatusCode::OK),
+            ("bob:bobpass", StatusCode::OK),
+            ("alice:bobpass", StatusCode::UNAUTHORIZED),
+            ("carol:alicepass", StatusCode::UNAUTHORIZED),
+            ("bob:bobpass", StatusCode::OK),
+            ("bob:alicepass", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = TestRequest::default()
+                .uri("/Cargo.toml")
+                .append_header((
+                    "Authorization",
+                    format!("Basic {}", base64::STANDARD.encode(cred)),
+                ))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", cred);
+        }
+        assert_eq!(auth_info.verified.lock().await.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_auth_lockout() {
+        let users = HashMap::from([
+            (String::from("alice"), bcrypt::hash("alicepass", 4).unwrap()),
+            (String::from("bob"), bcrypt::hash("bobpass", 4).unwrap()),
+            (String::from("dave"), bcrypt::hash("davepass", 4).unwrap()),
+        ]);
+        let auth_info = AuthInfo::new(users).with_limits(AuthLimits {
+            max_failures: 2,
+            ..AuthLimits::default()
+        });
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(app_data)
+                .app_data(auth_info)
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+        for (ip, cred, status) in [
+            (Some("192.0.2.1"), "alice:wrong1", StatusCode::UNAUTHORIZED),
+            (Some("192.0.2.1"), "alice:wrong2", StatusCode::UNAUTHORIZED),
+            // both the client and the user are locked out now
+            (
+                Some("192.0.2.1"),
+                "alice:alicepass",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                Some("192.0.2.2"),
+                "alice:alicepass",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (Some("192.0.2.3"), "carol:pass1", StatusCode::UNAUTHORIZED),
+            (Some("192.0.2.3"), "carol:pass2", StatusCode::UNAUTHORIZED),
+            (
+                Some("192.0.2.3"),
+                "bob:bobpass",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            // failures for unknown users only count against the client
+            (Some("192.0.2.4"), "bob:bobpass", StatusCode::OK),
+            // clients without an address, as behind a proxy on a Unix socket, only lock out the
+            // user they fail for
+            (None, "bob:wrong1", StatusCode::UNAUTHORIZED),
+            (None, "bob:wrong2", StatusCode::UNAUTHORIZED),
+            (None, "bob:bobpass", StatusCode::TOO_MANY_REQUESTS),
+            (None, "carol:pass3", StatusCode::UNAUTHORIZED),
+            (None, "dave:davepass", StatusCode::OK),
+        ] {
+            let mut req = TestRequest::default().uri("/Cargo.toml");
+            if let Some(ip) = ip {
+                req = req.peer_addr(format!("{}:40000", ip).parse().unwrap());
+            }
+            let req = req
+                .append_header((
+                    "Authorization",
+                    format!("Basic {}", base64::STANDARD.encode(cred)),
+                ))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{:?} {}", ip, cred);
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                assert!(resp.headers().contains_key(RETRY_AFTER));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_credentials_required() {
+        let users = HashMap::from([(String::from("alice"), bcrypt::hash("alicepass", 4).unwrap())]);
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.acl = crate::acl::Acl::new(
+            vec![config::AclRule {
+                path: String::from("/res_dir"),
+                users: vec![String::from("anonymous")],
+                perms: vec![config::Perm::Read, config::Perm::List],
+            }],
+            HashMap::new(),
+        )
+        .unwrap();
+        let (token, _) = app_state
+            .shares
+            .mint("/res_dir", Duration::from_secs(60), None, None)
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(web::Data::new(app_state))
+                .app_data(AuthInfo::new(users).with_credentials_required())
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+        // neither anonymous rules nor share links let requests without credentials in
+        for (uri, cred, status) in [
+            ("/res_dir/", None, StatusCode::UNAUTHORIZED),
+            (
+                &*format!("/res_dir/?share={}", token),
+                None,
+                StatusCode::UNAUTHORIZED,
+            ),
+            ("/res_dir/", Some("alice:alicepass"), StatusCode::OK),
+        ] {
+            let mut req = TestRequest::default().uri(uri);
+            if let Some(cred) = cred {
+                req = req.append_header((
+                    "Authorization",
+                    format!("Basic {}", base64::STANDARD.encode(cred)),
+                ));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "{} {:?}", uri, cred);
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::config::Perm;
+use crate::{AppState, acl, fs_proc};
+use actix_web::{HttpRequest, HttpResponse, web};
+use lru::LruCache;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::fs::Metadata;
+use std::hash::Hash;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+
+/// Identifies one version of a file. Replacing or rewriting the file changes the stamp.
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FileStamp {
+    mtime: Option<SystemTime>,
+    size: u64,
+    ino: u64,
+}
+
+impl FileStamp {
+    pub fn from_meta(meta: &Metadata) -> Self {
+        Self {
+            mtime: meta.modified().ok(),
+            size: meta.len(),
+            ino: meta.ino(),
+        }
+    }
+
+    pub async fn of(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::from_meta(&tokio::fs::metadata(path).await?))
+    }
+}
+
+/// A cached value derived from the contents of the `source` file.
+pub struct Cached<T> {
+    pub source: PathBuf,
+    pub stamp: FileStamp,
+    pub value: T,
+}
+
+/// Approximate heap memory held by a cached value, used to enforce byte budgets.
+pub trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for String {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for (String, Vec<u8>) {
+    fn byte_size(&self) -> usize {
+        self.0.len() + self.1.len()
+    }
+}
+
+impl<T: ByteSize> ByteSize for Cached<T> {
+    fn byte_size(&self) -> usize {
+        self.source.as_os_str().len() + self.value.byte_size()
+    }
+}
+
+/// Limits of a [`BoundedCache`], `0` means unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    /// Larger values are not cached at all, so one big image can not flush everything else.
+    pub max_entry_bytes: usize,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to stay within the limits.
+    pub evictions: u64,
+    /// Entries dropped because their source file changed.
+    pub invalidations: u64,
+    /// Values not cached because they exceed `max_entry_bytes`.
+    pub rejected: u64,
+}
+
+/// A LRU cache bounded by the number of entries and by the total size of its values.
+pub struct BoundedCache<K: Hash + Eq, V: ByteSize> {
+    lru: LruCache<K, V>,
+    limits: CacheLimits,
+    bytes: usize,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq, V: ByteSize> BoundedCache<K, V> {
+    pub fn new(limits: CacheLimits) -> Self {
+        Self {
+            lru: LruCache::unbounded(),
+            limits,
+            bytes: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.lru.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.lru.len(),
+            bytes: self.bytes,
+            max_entries: self.limits.max_entries,
+            max_bytes: self.limits.max_bytes,
+            ..self.stats
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let value = self.lru.get(key);
+        if value.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        value
+    }
+
+    /// Inserts the value and evicts the least recently used entries until the limits are met.
+    /// Returns `false` when the value is too big to be cached.
+    pub fn put(&mut self, key: K, value: V) -> bool {
+        let size = value.byte_size();
+        if let Some(old) = self.lru.pop(&key) {
+            self.bytes -= old.byte_size();
+        }
+        if self.limits.max_entry_bytes > 0 && size > self.limits.max_entry_bytes
+            || self.limits.max_bytes > 0 && size > self.limits.max_bytes
+        {
+            self.stats.rejected += 1;
+            return false;
+        }
+        self.lru.put(key, value);
+        self.bytes += size;
+        while self.limits.max_entries > 0 && self.lru.len() > self.limits.max_entries
+            || self.limits.max_bytes > 0 && self.bytes > self.limits.max_bytes
+        {
+            let Some((_, old)) = self.lru.pop_lru() else {
+                break;
+            };
+            self.bytes -= old.byte_size();
+            self.stats.evictions += 1;
+        }
+        true
+    }
+
+    /// Drops the entries matching `pred`, counting them as invalidated.
+    pub fn invalidate_where(&mut self, pred: impl Fn(&K, &V) -> bool) -> usize
+    where
+        K: Clone,
+    {
+        let keys = self
+            .lru
+            .iter()
+            .filter(|(key, value)| pred(key, value))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            if let Some(old) = self.lru.pop(key) {
+                self.bytes -= old.byte_size();
+                self.stats.invalidations += 1;
+            }
+        }
+        keys.len()
+    }
+}
+
+impl<K: Hash + Eq, T: ByteSize> BoundedCache<K, Cached<T>> {
+    /// Returns the entry for `key` when it was derived from the current version of its source
+    /// file. An outdated entry is dropped.
+    pub fn get_fresh(&mut self, key: &K, stamp: &FileStamp) -> Option<&T> {
+        if let Some(cached) = self.lru.peek(key)
+            && cached.stamp != *stamp
+        {
+            self.bytes -= cached.byte_size();
+            self.lru.pop(key);
+            self.stats.invalidations += 1;
+        }
+        self.get(key).map(|cached| &cached.value)
+    }
+
+    /// Drops all entries derived from `path` or from files under it, returns the number dropped.
+    pub fn evict_source(&mut self, path: &Path) -> usize
+    where
+        K: Clone,
+    {
+        self.invalidate_where(|_, cached| cached.source.starts_with(path))
+    }
+}
+
+/// The absolute form of a resolved path, which is what cache entries record as their source so
+/// they can be matched against file system events.
+pub fn source_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn evict_all(app_state: &AppState, path: &Path) {
+    app_state.epub_toc_cache.blocking_lock().evict_source(path);
+    app_state.epub_cont_cache.blocking_lock().evict_source(path);
+    app_state
+        .epub_doc_cache
+        .blocking_lock()
+        .invalidate_where(|key, _| key.path.starts_with(path));
+}
+
+#[derive(Serialize)]
+struct AllCacheStats {
+    epub_toc: CacheStats,
+    epub_cont: CacheStats,
+    epub_doc: CacheStats,
+}
+
+/// Access to the counters is controlled like reading a file at `/cache_stats`. Without the
+/// `cache_stats` setting the path is served from the contents root like any other.
+pub async fn cache_stats(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    if !app_state.cache_stats {
+        return fs_proc::fs_get(req, app_state).await;
+    }
+    if let Err(resp) = acl::authorize(&req, &app_state.acl, "/cache_stats", &[Perm::Read]) {
+        return resp;
+    }
+    let stats = AllCacheStats {
+        epub_toc: app_state.epub_toc_cache.lock().await.stats(),
+        epub_cont: app_state.epub_cont_cache.lock().await.stats(),
+        epub_doc: app_state.epub_doc_cache.lock().await.stats(),
+    };
+    HttpResponse::Ok().json(stats)
+}
+
+/// Watches the contents root and evicts cache entries as soon as their source files change,
+/// instead of waiting for the next access to notice it. The watcher stops when dropped.
+pub fn watch_root(app_state: &Arc<AppState>) -> notify::Result<RecommendedWatcher> {
+    let state: Weak<AppState> = Arc::downgrade(app_state);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let (Ok(event), Some(app_state)) = (event, state.upgrade()) else {
+            return;
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        for path in &event.paths {
+            evict_all(&app_state, path);
+        }
+    })?;
+    watcher.watch(&source_path(&app_state.root_dir), RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_config;
+    use crate::test_util::TempDir;
+    use actix_http::StatusCode;
+    use actix_web::test::{
+        TestRequest, call_and_read_body, call_and_read_body_json, call_service, init_service,
+    };
+    use actix_web::{App, web};
+    use std::time::Duration;
+
+    #[test]
+    fn test_get_fresh() {
+        let meta = std::fs::metadata("Cargo.toml").unwrap();
+        let stamp = FileStamp::from_meta(&meta);
+        let mut cache = BoundedCache::new(CacheLimits::default());
+        let cached = |path: &str| Cached {
+            source: source_path(Path::new(path)),
+            stamp,
+            value: String::from("1"),
+        };
+        cache.put("a", cached("Cargo.toml"));
+        assert_eq!(cache.get_fresh(&"a", &stamp), Some(&String::from("1")));
+        let other = FileStamp {
+            size: stamp.size + 1,
+            ..stamp
+        };
+        assert_eq!(cache.get_fresh(&"a", &other), None);
+        assert!(cache.is_empty());
+
+        cache.put("a", cached("src/lib.rs"));
+        cache.put("b", cached("Cargo.toml"));
+        assert_eq!(cache.evict_source(&source_path(Path::new("src"))), 1);
+        assert_eq!(cache.len(), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.invalidations), (1, 1, 2));
+        assert_eq!(stats.bytes, cached("Cargo.toml").byte_size());
+    }
+
+    #[test]
+    fn test_bounded_cache() {
+        let mut cache = BoundedCache::new(CacheLimits {
+            max_entries: 3,
+            max_bytes: 10,
+            max_entry_bytes: 6,
+        });
+        assert!(cache.put(1, String::from("aaaa")));
+        assert!(cache.put(2, String::from("bbbb")));
+        assert_eq!(cache.stats().bytes, 8);
+        // over the byte budget, the least recently used entry goes
+        assert!(cache.get(&1).is_some());
+        assert!(cache.put(3, String::from("cc")));
+        assert!(cache.put(4, String::from("d")));
+        assert!(cache.get(&2).is_none());
+        assert_eq!(cache.stats().bytes, 7);
+        // over the entry limit
+        assert!(cache.put(5, String::from("e")));
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.len(), 3);
+        // too big for a single entry
+        assert!(!cache.put(6, String::from("fffffff")));
+        assert!(cache.get(&6).is_none());
+        // replacing a value updates the byte count
+        assert!(cache.put(5, String::from("eeeee")));
+        let stats = cache.stats();
+        assert_eq!(stats.bytes, 8);
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+    }
+
+    fn temp_root(name: &str) -> TempDir {
+        let root = TempDir::new(name);
+        std::fs::copy("res_dir/v2.epub", root.join("book.epub")).unwrap();
+        root
+    }
+
+    #[actix_web::test]
+    async fn test_replaced_epub() {
+        let root = temp_root("replaced");
+        let app_data = web::Data::new(AppState::new(root.to_path_buf()));
+        let app = init_service(App::new().configure(app_config).app_data(app_data.clone())).await;
+        let req = TestRequest::default()
+            .uri("/epub_toc/book.epub")
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("Valentin Haüy"));
+
+        std::fs::copy("res_dir/nav.epub", root.join("book.epub")).unwrap();
+        let req = TestRequest::default()
+            .uri("/epub_toc/book.epub")
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains("Valentin Haüy"));
+        assert!(body.contains("FAIRY STORIES"));
+        assert_eq!(app_data.epub_toc_cache.lock().await.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_watch_root() {
+        let root = temp_root("watch");
+        let app_data = web::Data::new(AppState::new(root.to_path_buf()));
+        let _watcher = watch_root(&app_data.clone().into_inner()).unwrap();
+        let app = init_service(App::new().configure(app_config).app_data(app_data.clone())).await;
+        let req = TestRequest::default()
+            .uri("/epub_toc/book.epub")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(app_data.epub_toc_cache.lock().await.len(), 1);
+        assert_eq!(app_data.epub_doc_cache.lock().await.len(), 1);
+
+        std::fs::remove_file(root.join("book.epub")).unwrap();
+        for _ in 0..50 {
+            if app_data.epub_toc_cache.lock().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(app_data.epub_toc_cache.lock().await.is_empty());
+        assert!(app_data.epub_doc_cache.lock().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_cache_stats() {
+        // without the setting the path is looked up in the contents root
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app = init_service(App::new().configure(app_config).app_data(app_data)).await;
+        let req = TestRequest::default().uri("/cache_stats").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.cache_stats = true;
+        let app_data = web::Data::new(app_state);
+        let app = init_service(App::new().configure(app_config).app_data(app_data)).await;
+        for _ in 0..2 {
+            let req = TestRequest::default()
+                .uri("/epub_toc/res_dir/v2.epub")
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = TestRequest::default().uri("/cache_stats").to_request();
+        let stats: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(stats["epub_toc"]["entries"], 1);
+        assert_eq!(stats["epub_toc"]["hits"], 1);
+        assert_eq!(stats["epub_toc"]["misses"], 1);
+        assert!(stats["epub_toc"]["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(stats["epub_doc"]["entries"], 1);
+        assert_eq!(stats["epub_cont"]["entries"], 0);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::config::{self, Cli, Config};
+use crate::{acl, auth, templates, tls, vhost};
+use anyhow::{Result, anyhow};
+use std::path::Path;
+
+/// Checks that `path` of the setting `key` can be read, as a directory or a file.
+fn check_path(key: &str, path: &Path, dir: bool) -> Option<String> {
+    let checked = if dir {
+        std::fs::read_dir(path).map(|_| ())
+    } else {
+        std::fs::File::open(path).and_then(|file| {
+            if file.metadata()?.is_file() {
+                Ok(())
+            } else {
+                Err(std::io::Error::other("not a file"))
+            }
+        })
+    };
+    checked
+        .err()
+        .map(|err| format!("`{}` [{}]: {}", key, path.display(), err))
+}
+
+/// The problems of the settings of a site, prefixed with `prefix`.
+fn check_site(config: &Config, prefix: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    problems.extend(check_path("root_dir", &config.root_dir, true));
+    if let Some(dir) = &config.templates_dir {
+        match check_path("templates_dir", dir, true) {
+            Some(problem) => problems.push(problem),
+            None => {
+                if let Err(err) = templates::Templates::new(Some(dir)) {
+                    problems.push(format!("`templates_dir`: {:#}", err));
+                }
+            }
+        }
+    }
+    match auth::load_users(config) {
+        Ok(users) => {
+            let mut users = users.into_iter().collect::<Vec<_>>();
+            users.sort();
+            for (name, hash) in users {
+                if let Err(err) = auth::check_hash(&hash) {
+                    problems.push(format!("user [{}]: {}", name, err));
+                }
+            }
+        }
+        Err(err) => problems.push(format!("{:#}", err)),
+    }
+    if let Err(err) = acl::Acl::new(config.acl.clone(), config.groups.clone()) {
+        problems.push(format!("`acl`: {:#}", err));
+    }
+    problems
+        .into_iter()
+        .map(|problem| format!("{}{}", prefix, problem))
+        .collect()
+}
+
+/// The problems of the files the config refers to, which are only found when they are used.
+fn check_files(config: &Config) -> Vec<String> {
+    let mut problems = check_site(config, "");
+    for entry in &config.vhosts {
+        let prefix = format!("vhost {:?}: ", entry.hosts);
+        problems.extend(check_site(&vhost::vhost_config(config, entry), &prefix));
+        if let (Some(cert_path), Some(key_path)) = (&entry.cert_path, &entry.key_path)
+            && let Err(err) = tls::check_pair(cert_path, key_path)
+        {
+            problems.push(format!("{}{:#}", prefix, err));
+        }
+    }
+    for path in config
+        .listen
+        .iter()
+        .filter_map(|entry| entry.path.as_deref())
+    {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            problems.extend(check_path("listen", dir, true));
+        }
+    }
+    if let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path)
+        && let Err(err) = tls::check_pair(cert_path, key_path)
+    {
+        problems.push(format!("{:#}", err));
+    }
+    if let Some(ca_path) = &config.client_ca_path
+        && let Err(err) = tls::client_roots(ca_path)
+    {
+        problems.push(format!("`client_ca_path`: {:#}", err));
+    }
+    if let Err(err) = tls::provider_with_suites(&config.tls_cipher_suites) {
+        problems.push(format!("`tls_cipher_suites`: {:#}", err));
+    }
+    problems
+}
+
+/// The problems of the settings and of the files they refer to, all of them rather than only
+/// those of the first kind found.
+fn check_config(cli: Cli) -> Result<Vec<String>> {
+    let (config, mut problems) = config::config_with_problems(cli)?;
+    problems.extend(check_files(&config));
+    Ok(problems)
+}
+
+/// Checks the config for the `check-config` subcommand, printing every problem found.
+pub fn run_check_config(cli: Cli) -> Result<()> {
+    let problems = check_config(cli)?;
+    if problems.is_empty() {
+        println!("The config is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(anyhow!("Found {} problem(s) in the config", problems.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_files() {
+        let cli = config::parse_cli_from(["my-cont-srv", "-c", "res_dir/config.toml"]);
+        let config = config::get_config(cli).unwrap();
+        assert_eq!(check_files(&config), Vec::<String>::new());
+
+        let cli = config::parse_cli_from([
+            "my-cont-srv",
+            "--root-dir",
+            "res_dir/missing",
+            "--cert-path",
+            "res_dir/cert.pem",
+            "--key-path",
+            "res_dir/vhost_key.pem",
+            "--user-name",
+            "alice",
+            "--password-hash",
+            "$2b$12$abc",
+            "--acl",
+            r#"[{ path = "/", users = ["@team"], perms = ["read"] }]"#,
+            "--tls-cipher-suites",
+            r#"["TLS_NOPE"]"#,
+        ]);
+        let config = config::get_config(cli).unwrap();
+        let problems = check_files(&config);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        for (problem, expected) in problems.iter().zip([
+            "`root_dir` [res_dir/missing]: ",
+            "user [alice]: Invalid bcrypt hash",
+            "`acl`: ACL rule [/]: unknown group [@team]",
+            "Invalid certificate [res_dir/cert.pem] or key [res_dir/vhost_key.pem]",
+            "`tls_cipher_suites`: Unknown cipher suite [TLS_NOPE]",
+        ]) {
+            assert!(problem.starts_with(expected), "{}", problem);
+        }
+
+        // problems of the settings are reported together with those of the files
+        let cli = config::parse_cli_from([
+            "my-cont-srv",
+            "--root-dir",
+            "res_dir/missing",
+            "--user-name",
+            "alice",
+        ]);
+        let problems = check_config(cli).unwrap();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("`user_name` and `password_hash`"));
+        assert!(problems[1].starts_with("`root_dir` [res_dir/missing]: "));
+    }
+}
@@ -1,48 +1,661 @@
 use anyhow::{Result, anyhow};
-use clap::{Parser, value_parser};
-use serde::Deserialize;
-use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
-#[derive(Parser)]
+#[derive(Parser, Clone, Default)]
 #[command(name = "my-cont-srv")]
 pub struct Cli {
     #[arg(
         short,
         long,
-        default_value = "0.0.0.0",
-        help = "The address the server bind to. Specify '::' to bind to all addresses"
+        env = "MY_CONT_SRV_CONFIG_FILE",
+        help = "The path of the config file with toml format"
     )]
-    address: String,
+    pub config_file: Option<PathBuf>,
 
-    #[arg(short, long, default_value_t = 1131, value_parser = value_parser!(u16).range(1..),
-        help = "The server listening port")]
-    port: u16,
+    #[arg(
+        long,
+        help = "Hash the password and exit after printing the result. The hash can be used in the config file."
+    )]
+    pub hash_password: Option<String>,
 
-    #[arg(short, long, default_value = ".", help = "The contents root directory")]
-    root_dir: PathBuf,
+    #[arg(
+        long,
+        help = "Print the effective settings and where each of them comes from, then exit"
+    )]
+    pub print_config: bool,
 
-    #[arg(short, long, help = "The path of the config file with toml format")]
-    config_file: Option<PathBuf>,
+    #[command(flatten)]
+    settings: Settings,
 
+    /// The settings given by environment variables rather than flags.
+    #[arg(skip)]
+    from_env: HashSet<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    #[command(about = "Manage the users in a users file")]
+    User {
+        #[arg(
+            short,
+            long,
+            help = "The users file to edit. Defaults to `users_file` of the config file"
+        )]
+        file: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    #[command(about = "Print a share link for a file or directory, signed with `share_secret`")]
+    Share {
+        #[arg(help = "The path to share, relative to the contents root")]
+        path: String,
+        #[arg(
+            long,
+            help = "A host name of the vhost to share from, the top level site if not set"
+        )]
+        host: Option<String>,
+        #[arg(long, default_value_t = DEFAULT_SHARE_EXPIRES_IN, help = "Seconds the link stays valid")]
+        expires_in: u64,
+        #[arg(long, help = "Max number of downloads of the shared files")]
+        max_downloads: Option<u64>,
+        #[arg(long, help = "A password required to open the link")]
+        password: Option<String>,
+        #[arg(
+            long,
+            default_value = "",
+            help = "Prepended to the link, e.g. `https://example.com:1131`"
+        )]
+        base_url: String,
+    },
+    #[command(
+        about = "Generate a certificate and key for HTTPS, self-signed or signed by a local CA"
+    )]
+    GenCert(GenCertArgs),
+    #[command(about = "Check the config and the files it refers to, reporting every problem found")]
+    CheckConfig,
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct GenCertArgs {
+    #[arg(
+        long = "san",
+        required = true,
+        help = "A host name or IP address the certificate is for, can be given several times"
+    )]
+    pub sans: Vec<String>,
+    #[arg(long, default_value_t = 365, help = "Days the certificate is valid")]
+    pub days: u32,
     #[arg(
         long,
-        help = "Hash the password and exit after printing the result. The hash can be used in the config file."
+        requires = "ca_key",
+        help = "The certificate (PEM) of the CA signing it"
     )]
-    pub hash_password: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "ca_cert",
+        help = "The private key (PEM) of the CA signing it"
+    )]
+    pub ca_key: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value = "cert.pem",
+        help = "Where the certificate is written"
+    )]
+    pub cert_out: PathBuf,
+    #[arg(
+        long,
+        default_value = "key.pem",
+        help = "Where the private key is written"
+    )]
+    pub key_out: PathBuf,
+    #[arg(long, help = "Overwrite existing files")]
+    pub force: bool,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum UserAction {
+    #[command(about = "Add a user")]
+    Add {
+        name: String,
+        #[arg(long, help = "The password. It is read from stdin when omitted")]
+        password: Option<String>,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            help = "The password hashing scheme"
+        )]
+        scheme: HashScheme,
+    },
+    #[command(about = "Remove a user")]
+    Remove { name: String },
+    #[command(about = "Set a new password or hashing scheme for a user")]
+    Rehash {
+        name: String,
+        #[arg(long, help = "The password. It is read from stdin when omitted")]
+        password: Option<String>,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            help = "The password hashing scheme"
+        )]
+        scheme: HashScheme,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum HashScheme {
+    #[default]
+    Bcrypt,
+    Argon2,
+}
+
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0";
+pub const DEFAULT_PORT: u16 = 1131;
+pub const DEFAULT_ROOT_DIR: &str = ".";
+pub const DEFAULT_WORKERS: usize = 2;
+pub const DEFAULT_EPUB_CONCURRENCY: usize = 4;
+pub const DEFAULT_EPUB_TIMEOUT: u64 = 30;
+pub const DEFAULT_EPUB_TOC_CACHE_BYTES: usize = 1 << 20;
+pub const DEFAULT_EPUB_CONT_CACHE_BYTES: usize = 64 << 20;
+pub const DEFAULT_CACHE_MAX_ENTRY_BYTES: usize = 8 << 20;
+pub const DEFAULT_EPUB_DOC_CACHE_ENTRIES: usize = 10;
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: u64 = 1800;
+pub const DEFAULT_SESSION_REMEMBER_DAYS: u64 = 30;
+pub const DEFAULT_AUTH_MAX_FAILURES: u32 = 5;
+pub const DEFAULT_AUTH_LOCKOUT: u64 = 60;
+pub const DEFAULT_AUTH_MAX_LOCKOUT: u64 = 3600;
+pub const DEFAULT_AUTH_VERIFY_CONCURRENCY: usize = 2;
+pub const DEFAULT_SHARE_EXPIRES_IN: u64 = 7 * 24 * 3600;
+pub const MAX_SHARE_EXPIRES_IN: u64 = 10 * 365 * 24 * 3600;
+pub const DEFAULT_TLS_SESSION_CACHE: usize = 256;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+/// How symlinks found while resolving a request path are treated.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Follow symlinks wherever they point to.
+    Follow,
+    /// Follow symlinks only when the target is still inside the contents root.
+    #[default]
+    FollowWithinRoot,
+    /// Refuse any path containing a symlink.
+    Deny,
+}
+
+/// The oldest TLS version accepted.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
 }
 
-#[derive(Deserialize, Debug)]
-struct TomlConfig {
+/// An application protocol offered to TLS clients through ALPN.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum AlpnProtocol {
+    #[serde(rename = "h2")]
+    H2,
+    #[serde(rename = "http/1.1")]
+    Http11,
+}
+
+/// How client certificates are used when `client_ca_path` is set.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Clients may present a certificate, which signs them in. Others authenticate as usual.
+    #[default]
+    Optional,
+    /// The TLS handshake fails without a valid client certificate.
+    Required,
+    /// Requests need a client certificate or a password (or session), anonymous access and
+    /// share links are refused.
+    CertOrPassword,
+}
+
+/// The field of a client certificate taken as the user name.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CertUserField {
+    /// The common name of the subject.
+    #[default]
+    CommonName,
+    /// The first email address of the subject alternative names.
+    Email,
+    /// The first DNS name of the subject alternative names.
+    DnsName,
+}
+
+/// What an access control rule allows.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Perm {
+    /// Download files and read epubs.
+    Read,
+    /// View directory listings.
+    List,
+    /// Modify contents. Nothing is writable yet, so it is accepted but not used.
+    Write,
+}
+
+/// An entry of the `[[acl]]` table.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    /// A path prefix such as `/public`, or a glob such as `/books/**/*.epub`.
+    pub path: String,
+    /// User names, `@group` names, `*` for any signed in user, or `anonymous` for everyone.
+    pub users: Vec<String>,
+    pub perms: Vec<Perm>,
+}
+
+/// An entry of the `[[users]]` table.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserEntry {
+    pub name: String,
+    pub password_hash: String,
+}
+
+/// An entry of the `[[vhost]]` table, a site served for some host names. Unset auth settings
+/// are taken from the top level.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VhostEntry {
+    /// Host names matched against the SNI server name and the `Host` header.
+    pub hosts: Vec<String>,
+    /// The certificate for the host names, the top level one is used when not set.
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    pub root_dir: PathBuf,
+    /// Users of the site, replacing the top level users together with `users_file`.
+    pub users: Option<Vec<UserEntry>>,
+    pub users_file: Option<PathBuf>,
+    pub groups: Option<HashMap<String, Vec<String>>>,
+    pub acl: Option<Vec<AclRule>>,
+}
+
+/// An entry of the `[[listen]]` table, a TCP address or a Unix socket the server listens on.
+/// When there are any, they replace `address` and `port`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenEntry {
+    /// The address to bind `port` on, the top level `address` when not set.
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    /// The path of a Unix socket, instead of `address` and `port`.
+    pub path: Option<PathBuf>,
+    /// Serve HTTPS, the default for TCP addresses when `cert_path` is set. Unix sockets are
+    /// always plain.
+    pub tls: Option<bool>,
+    /// The permissions of the Unix socket file, such as `0o660`.
+    pub mode: Option<u32>,
+}
+
+impl ListenEntry {
+    /// Whether the entry serves HTTPS, given whether `cert_path` and `key_path` are set.
+    pub fn tls(&self, tls_enabled: bool) -> bool {
+        self.path.is_none() && self.tls.unwrap_or(tls_enabled)
+    }
+}
+
+/// A `Vec` which clap takes as the value of one flag rather than repeated flags, the list is
+/// given as a TOML array.
+type List<T> = Vec<T>;
+
+/// The settings of one layer: the config file, or the command line together with the
+/// environment, where each key is a `--flag` and a `MY_CONT_SRV_*` variable. Values given there
+/// are TOML values, except that strings may be unquoted.
+#[derive(Args, Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    #[arg(
+        short,
+        long,
+        env = "MY_CONT_SRV_ADDRESS",
+        help = "The address the server binds to. Specify '::' to bind to all addresses"
+    )]
     pub address: Option<String>,
+    #[arg(
+        short,
+        long,
+        env = "MY_CONT_SRV_PORT",
+        value_parser = parse_setting::<u16>,
+        help = "The server listening port"
+    )]
     pub port: Option<u16>,
+    #[arg(
+        short,
+        long,
+        env = "MY_CONT_SRV_ROOT_DIR",
+        help = "The contents root directory"
+    )]
     pub root_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CERT_PATH",
+        help = "The certificate chain (PEM) enabling HTTPS"
+    )]
     pub cert_path: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_KEY_PATH",
+        help = "The private key (PEM) of the certificate"
+    )]
     pub key_path: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_USER_NAME",
+        help = "A user allowed to sign in"
+    )]
     pub user_name: Option<String>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_PASSWORD_HASH",
+        hide_env_values = true,
+        help = "The bcrypt or argon2 password hash of `user_name`"
+    )]
     pub password_hash: Option<String>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_WORKERS",
+        value_parser = parse_setting::<usize>,
+        help = "Number of worker threads"
+    )]
     pub workers: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_SYMLINK_POLICY",
+        value_parser = parse_setting::<SymlinkPolicy>,
+        help = "How symlinks are treated: follow, follow_within_root or deny"
+    )]
+    pub symlink_policy: Option<SymlinkPolicy>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TEMPLATES_DIR",
+        help = "A directory with templates replacing the built-in ones"
+    )]
+    pub templates_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_EPUB_CONCURRENCY",
+        value_parser = parse_setting::<usize>,
+        help = "Max number of epub parsing jobs running at the same time"
+    )]
+    pub epub_concurrency: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_EPUB_TIMEOUT",
+        value_parser = parse_setting::<u64>,
+        help = "Max seconds an epub request may take"
+    )]
+    pub epub_timeout: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_WATCH_FILES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Watch the contents root to evict changed epubs from the caches"
+    )]
+    pub watch_files: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_EPUB_TOC_CACHE_BYTES",
+        value_parser = parse_setting::<usize>,
+        help = "Total bytes of cached epub TOC pages, 0 means unlimited"
+    )]
+    pub epub_toc_cache_bytes: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_EPUB_CONT_CACHE_BYTES",
+        value_parser = parse_setting::<usize>,
+        help = "Total bytes of cached epub resources, 0 means unlimited"
+    )]
+    pub epub_cont_cache_bytes: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CACHE_MAX_ENTRY_BYTES",
+        value_parser = parse_setting::<usize>,
+        help = "Bigger TOC pages and resources are not cached, 0 means unlimited"
+    )]
+    pub cache_max_entry_bytes: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_EPUB_DOC_CACHE_ENTRIES",
+        value_parser = parse_setting::<usize>,
+        help = "Number of opened epub files kept, 0 means unlimited"
+    )]
+    pub epub_doc_cache_entries: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CACHE_STATS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Serve the cache counters at /cache_stats instead of a root entry of that name"
+    )]
+    pub cache_stats: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_USERS",
+        value_parser = parse_setting::<Vec<UserEntry>>,
+        help = "Users as a TOML array, e.g. [{ name = \"alice\", password_hash = \"...\" }]"
+    )]
+    pub users: Option<List<UserEntry>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_USERS_FILE",
+        help = "An htpasswd-style file with `name:hash` lines"
+    )]
+    pub users_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_GROUPS",
+        value_parser = parse_setting::<HashMap<String, Vec<String>>>,
+        help = "Groups as a TOML table, e.g. { team = [\"alice\", \"bob\"] }"
+    )]
+    pub groups: Option<HashMap<String, Vec<String>>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_ACL",
+        value_parser = parse_setting::<Vec<AclRule>>,
+        help = "Access control rules as a TOML array of tables with path, users and perms"
+    )]
+    pub acl: Option<List<AclRule>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_FORM_LOGIN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Offer a login page issuing session cookies"
+    )]
+    pub form_login: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_SESSION_IDLE_TIMEOUT",
+        value_parser = parse_setting::<u64>,
+        help = "Seconds of inactivity after which a login session ends"
+    )]
+    pub session_idle_timeout: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_SESSION_REMEMBER_DAYS",
+        value_parser = parse_setting::<u64>,
+        help = "Days a \"remember me\" login session lasts"
+    )]
+    pub session_remember_days: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_AUTH_MAX_FAILURES",
+        value_parser = parse_setting::<u32>,
+        help = "Failed logins before a client or user is locked out, 0 disables it"
+    )]
+    pub auth_max_failures: Option<u32>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_AUTH_LOCKOUT",
+        value_parser = parse_setting::<u64>,
+        help = "Seconds of the first lockout"
+    )]
+    pub auth_lockout: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_AUTH_MAX_LOCKOUT",
+        value_parser = parse_setting::<u64>,
+        help = "Max seconds of a lockout"
+    )]
+    pub auth_max_lockout: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_AUTH_VERIFY_CONCURRENCY",
+        value_parser = parse_setting::<usize>,
+        help = "Max number of password hashes verified at the same time"
+    )]
+    pub auth_verify_concurrency: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_SHARE_SECRET",
+        hide_env_values = true,
+        help = "The key signing share links"
+    )]
+    pub share_secret: Option<String>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CLIENT_CA_PATH",
+        help = "CA certificates (PEM) client certificates are verified against"
+    )]
+    pub client_ca_path: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CLIENT_AUTH",
+        value_parser = parse_setting::<ClientAuth>,
+        help = "Clients without a certificate: optional, required or cert_or_password"
+    )]
+    pub client_auth: Option<ClientAuth>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_CLIENT_CERT_USER",
+        value_parser = parse_setting::<CertUserField>,
+        help = "The client certificate field naming the user: common_name, email or dns_name"
+    )]
+    pub client_cert_user: Option<CertUserField>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_VHOST",
+        value_parser = parse_setting::<Vec<VhostEntry>>,
+        help = "Virtual hosts as a TOML array of tables"
+    )]
+    pub vhost: Option<List<VhostEntry>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TLS_MIN_VERSION",
+        value_parser = parse_setting::<TlsVersion>,
+        help = "The oldest TLS version accepted: 1.2 or 1.3"
+    )]
+    pub tls_min_version: Option<TlsVersion>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TLS_CIPHER_SUITES",
+        value_parser = parse_setting::<Vec<String>>,
+        help = "Allowed cipher suites as a TOML array"
+    )]
+    pub tls_cipher_suites: Option<List<String>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TLS_ALPN",
+        value_parser = parse_setting::<Vec<AlpnProtocol>>,
+        help = "Protocols offered through ALPN as a TOML array of \"h2\" and \"http/1.1\""
+    )]
+    pub tls_alpn: Option<List<AlpnProtocol>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TLS_SESSION_TICKETS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Issue TLS session tickets"
+    )]
+    pub tls_session_tickets: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_TLS_SESSION_CACHE",
+        value_parser = parse_setting::<usize>,
+        help = "Number of TLS sessions kept for resumption, 0 disables it"
+    )]
+    pub tls_session_cache: Option<usize>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_HSTS_MAX_AGE",
+        value_parser = parse_setting::<u64>,
+        help = "max-age of the Strict-Transport-Security header, 0 disables it"
+    )]
+    pub hsts_max_age: Option<u64>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_HSTS_INCLUDE_SUBDOMAINS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Add includeSubDomains to the Strict-Transport-Security header"
+    )]
+    pub hsts_include_subdomains: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_HTTP_REDIRECT_PORT",
+        value_parser = parse_setting::<u16>,
+        help = "Port of a plain HTTP listener redirecting to HTTPS"
+    )]
+    pub http_redirect_port: Option<u16>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_LISTEN",
+        value_parser = parse_setting::<Vec<ListenEntry>>,
+        help = "Addresses and Unix sockets to listen on instead of --address and --port, as a TOML array of tables"
+    )]
+    pub listen: Option<List<ListenEntry>>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_WATCH_CONFIG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = parse_setting::<bool>,
+        help = "Reload the config file when it changes"
+    )]
+    pub watch_config: Option<bool>,
+    #[arg(
+        long,
+        env = "MY_CONT_SRV_SHUTDOWN_TIMEOUT",
+        value_parser = parse_setting::<u64>,
+        help = "Seconds requests being served get to finish on SIGTERM or SIGINT"
+    )]
+    pub shutdown_timeout: Option<u64>,
 }
 
+/// The effective settings, deserialized from the layers of [`Settings`] merged over the defaults.
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub address: String,
     pub port: u16,
@@ -52,66 +665,374 @@ pub struct Config {
     pub user_name: Option<String>,
     pub password_hash: Option<String>,
     pub workers: usize,
+    pub symlink_policy: SymlinkPolicy,
+    pub templates_dir: Option<PathBuf>,
+    /// Max number of epub parsing/decompression jobs running at the same time.
+    pub epub_concurrency: usize,
+    /// Max seconds an epub request may take before 503 is returned.
+    pub epub_timeout: u64,
+    /// Watch the contents root to evict cached epub contents as soon as files change.
+    pub watch_files: bool,
+    /// Total bytes of generated epub TOC pages kept in memory, `0` means unlimited.
+    pub epub_toc_cache_bytes: usize,
+    /// Total bytes of epub resources kept in memory, `0` means unlimited.
+    pub epub_cont_cache_bytes: usize,
+    /// Bigger TOC pages and resources are served without being cached, `0` means unlimited.
+    pub cache_max_entry_bytes: usize,
+    /// Number of opened epub files kept in memory, `0` means unlimited.
+    pub epub_doc_cache_entries: usize,
+    /// Serve the cache counters at `/cache_stats`, which otherwise is a path like any other.
+    pub cache_stats: bool,
+    /// Users besides `user_name`, with their bcrypt or argon2 password hashes.
+    pub users: Vec<UserEntry>,
+    /// An htpasswd-style file with `name:hash` lines.
+    pub users_file: Option<PathBuf>,
+    /// Group name to the user names in it.
+    pub groups: HashMap<String, Vec<String>>,
+    /// Access control rules, the first rule matching a path applies.
+    pub acl: Vec<AclRule>,
+    /// Offer a login page issuing session cookies, besides basic auth.
+    pub form_login: bool,
+    /// Seconds of inactivity after which a login session ends.
+    pub session_idle_timeout: u64,
+    /// Days a "remember me" login session lasts.
+    pub session_remember_days: u64,
+    /// Failed logins from one client or for one user before it is locked out, `0` disables it.
+    pub auth_max_failures: u32,
+    /// Seconds of the first lockout, doubled with every further failure.
+    pub auth_lockout: u64,
+    /// Max seconds of a lockout.
+    pub auth_max_lockout: u64,
+    /// Max number of password hashes verified at the same time.
+    pub auth_verify_concurrency: usize,
+    /// The key signing share links. Without it a random key is used, and links stop working
+    /// when the server restarts.
+    pub share_secret: Option<String>,
+    /// CA certificates (PEM) client certificates are verified against, enabling mutual TLS.
+    pub client_ca_path: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    /// The field of a client certificate used as the user name.
+    pub client_cert_user: CertUserField,
+    /// Sites for other host names, requests for unknown hosts are served by the top level site.
+    #[serde(rename = "vhost")]
+    pub vhosts: Vec<VhostEntry>,
+    pub tls_min_version: TlsVersion,
+    /// Names of the allowed cipher suites, such as `TLS13_AES_256_GCM_SHA384`. Empty allows the
+    /// defaults of rustls.
+    pub tls_cipher_suites: Vec<String>,
+    /// The protocols offered through ALPN, `h2` is preferred when both are.
+    pub tls_alpn: Vec<AlpnProtocol>,
+    /// Issue session tickets, letting clients resume sessions without server side state.
+    pub tls_session_tickets: bool,
+    /// Number of sessions kept for resumption by session ID, `0` disables it.
+    pub tls_session_cache: usize,
+    /// `max-age` of the `Strict-Transport-Security` header sent over HTTPS, `0` disables it.
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    /// Port of a plain HTTP listener redirecting every request to HTTPS.
+    pub http_redirect_port: Option<u16>,
+    /// The addresses and Unix sockets to listen on, `address` and `port` when empty.
+    pub listen: Vec<ListenEntry>,
+    /// Reload the config file when it changes, besides on SIGHUP.
+    pub watch_config: bool,
+    /// Seconds requests being served get to finish when the server is stopped, before their
+    /// connections are closed.
+    pub shutdown_timeout: u64,
+    /// The command line the config was made from, parsed again on reload.
+    #[serde(skip)]
+    pub cli: Cli,
 }
 
 #[inline]
 pub fn parse_cli() -> Cli {
-    Cli::parse()
+    parse_cli_from(std::env::args_os())
 }
 
-#[inline]
-pub fn parse_cli_from(args: Vec<String>) -> Cli {
-    Cli::parse_from(args)
+pub fn parse_cli_from<I, T>(args: I) -> Cli
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Cli::command().get_matches_from(args);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    cli.from_env = matches
+        .ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::EnvVariable))
+        .map(|id| id.to_string())
+        .collect();
+    cli
 }
 
-fn parse_config_file(file: &PathBuf) -> Result<TomlConfig> {
-    let config_str = std::fs::read_to_string(file)?;
-    let config: TomlConfig = toml::from_str(&config_str)?;
-    Ok(config)
+/// Parses a setting given by a flag or an environment variable, as a string or else as a TOML
+/// value.
+fn parse_setting<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    #[derive(Deserialize)]
+    struct Wrapper<T> {
+        value: T,
+    }
+    if let Ok(setting) = T::deserialize(toml::Value::from(value)) {
+        return Ok(setting);
+    }
+    toml::from_str::<Wrapper<T>>(&format!("value = {}", value))
+        .map(|wrapper| wrapper.value)
+        .map_err(|err| err.message().to_string())
 }
 
-pub fn get_config(cli: Cli) -> Result<Config> {
-    let mut config = Config {
-        port: cli.port,
-        root_dir: cli.root_dir,
-        address: cli.address,
-        cert_path: None,
-        key_path: None,
-        user_name: None,
-        password_hash: None,
-        workers: 2,
-    };
-
-    if let Some(path) = cli.config_file {
-        let toml_cfg = parse_config_file(&path)?;
-        if (toml_cfg.cert_path.is_some() && toml_cfg.key_path.is_none())
-            || (toml_cfg.cert_path.is_none() && toml_cfg.key_path.is_some())
-        {
-            eprintln!("Both cert file and key file are needed for HTTPS support!");
-            return Err(anyhow!("Missing cert file or key file"));
+/// Parses the config file, errors are reported as `file:line:column: message`.
+fn parse_config_file(file: &Path) -> Result<Settings> {
+    let config_str = std::fs::read_to_string(file)
+        .map_err(|err| anyhow!("Reading config file [{}] failed: {}", file.display(), err))?;
+    toml::from_str(&config_str).map_err(|err| {
+        let offset = err.span().map_or(0, |span| span.start);
+        let before = &config_str[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+        let column = before[line_start..].chars().count() + 1;
+        anyhow!("{}:{}:{}: {}", file.display(), line, column, err.message())
+    })
+}
+
+/// The values of the settings given nowhere else.
+fn defaults() -> Settings {
+    Settings {
+        address: Some(String::from(DEFAULT_ADDRESS)),
+        port: Some(DEFAULT_PORT),
+        root_dir: Some(PathBuf::from(DEFAULT_ROOT_DIR)),
+        workers: Some(DEFAULT_WORKERS),
+        symlink_policy: Some(SymlinkPolicy::default()),
+        epub_concurrency: Some(DEFAULT_EPUB_CONCURRENCY),
+        epub_timeout: Some(DEFAULT_EPUB_TIMEOUT),
+        watch_files: Some(false),
+        epub_toc_cache_bytes: Some(DEFAULT_EPUB_TOC_CACHE_BYTES),
+        epub_cont_cache_bytes: Some(DEFAULT_EPUB_CONT_CACHE_BYTES),
+        cache_max_entry_bytes: Some(DEFAULT_CACHE_MAX_ENTRY_BYTES),
+        epub_doc_cache_entries: Some(DEFAULT_EPUB_DOC_CACHE_ENTRIES),
+        cache_stats: Some(false),
+        users: Some(Vec::new()),
+        groups: Some(HashMap::new()),
+        acl: Some(Vec::new()),
+        form_login: Some(false),
+        session_idle_timeout: Some(DEFAULT_SESSION_IDLE_TIMEOUT),
+        session_remember_days: Some(DEFAULT_SESSION_REMEMBER_DAYS),
+        auth_max_failures: Some(DEFAULT_AUTH_MAX_FAILURES),
+        auth_lockout: Some(DEFAULT_AUTH_LOCKOUT),
+        auth_max_lockout: Some(DEFAULT_AUTH_MAX_LOCKOUT),
+        auth_verify_concurrency: Some(DEFAULT_AUTH_VERIFY_CONCURRENCY),
+        client_auth: Some(ClientAuth::default()),
+        client_cert_user: Some(CertUserField::default()),
+        vhost: Some(Vec::new()),
+        tls_min_version: Some(TlsVersion::default()),
+        tls_cipher_suites: Some(Vec::new()),
+        tls_alpn: Some(vec![AlpnProtocol::H2, AlpnProtocol::Http11]),
+        tls_session_tickets: Some(false),
+        tls_session_cache: Some(DEFAULT_TLS_SESSION_CACHE),
+        hsts_max_age: Some(0),
+        hsts_include_subdomains: Some(false),
+        listen: Some(Vec::new()),
+        watch_config: Some(false),
+        shutdown_timeout: Some(DEFAULT_SHUTDOWN_TIMEOUT),
+        ..Default::default()
+    }
+}
+
+/// Where the value of a setting comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Default,
+    File,
+    Env,
+    CommandLine,
+}
+
+/// The settings of all layers merged by precedence: flags, environment variables, the config
+/// file and the defaults. Returns them with the source of each.
+fn layered_settings(cli: &Cli) -> Result<(toml::Table, HashMap<String, Source>)> {
+    let mut layers = vec![(toml::Table::try_from(defaults())?, Source::Default)];
+    if let Some(path) = &cli.config_file {
+        layers.push((
+            toml::Table::try_from(parse_config_file(path)?)?,
+            Source::File,
+        ));
+    }
+    layers.push((toml::Table::try_from(&cli.settings)?, Source::CommandLine));
+
+    let mut settings = toml::Table::new();
+    let mut sources = HashMap::new();
+    for (layer, source) in layers {
+        for (key, value) in layer {
+            let source = if source == Source::CommandLine && cli.from_env.contains(&key) {
+                Source::Env
+            } else {
+                source
+            };
+            sources.insert(key.clone(), source);
+            settings.insert(key, value);
         }
-        if (toml_cfg.user_name.is_some() && toml_cfg.password_hash.is_none())
-            || (toml_cfg.user_name.is_none() && toml_cfg.password_hash.is_some())
-        {
-            eprintln!("Both user name and password hash are needed for user authentication!");
-            return Err(anyhow!("Missing user name or password hash"));
+    }
+    Ok((settings, sources))
+}
+
+/// Keys whose values `--print-config` does not show, at any depth.
+const SECRET_KEYS: [&str; 2] = ["share_secret", "password_hash"];
+
+/// `value` of `key` with the secrets in it replaced, including those in tables and arrays such
+/// as `users` and `vhost`.
+fn redact(key: &str, value: toml::Value) -> toml::Value {
+    if SECRET_KEYS.contains(&key) {
+        return toml::Value::from("<redacted>");
+    }
+    match value {
+        toml::Value::Table(table) => toml::Value::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = redact(&key, value);
+                    (key, value)
+                })
+                .collect(),
+        ),
+        toml::Value::Array(values) => {
+            toml::Value::Array(values.into_iter().map(|value| redact(key, value)).collect())
         }
-        if let Some(address) = toml_cfg.address {
-            config.address = address;
+        value => value,
+    }
+}
+
+/// Prints the effective settings for `--print-config`, each with where it comes from. Secrets
+/// and password hashes are redacted.
+pub fn print_config(cli: &Cli) -> Result<()> {
+    let (settings, sources) = layered_settings(cli)?;
+    for (key, value) in settings {
+        let source = match sources[&key] {
+            Source::Default => String::from("default"),
+            Source::File => format!(
+                "config file {}",
+                cli.config_file
+                    .as_deref()
+                    .unwrap_or(Path::new(""))
+                    .display()
+            ),
+            Source::Env => format!("environment variable MY_CONT_SRV_{}", key.to_uppercase()),
+            Source::CommandLine => format!("command line --{}", key.replace('_', "-")),
+        };
+        let value = redact(&key, value);
+        println!("{} = {}  # {}", key, value, source);
+    }
+    Ok(())
+}
+
+/// The problems of settings depending on each other, each naming the keys involved.
+fn check_settings(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    let tls = config.cert_path.is_some();
+    if config.port == 0 && config.listen.is_empty() {
+        problems.push(String::from("`port` must not be 0"));
+    }
+    if config.cert_path.is_some() != config.key_path.is_some() {
+        problems.push(String::from(
+            "`cert_path` and `key_path` are both needed for HTTPS",
+        ));
+    }
+    if config.user_name.is_some() != config.password_hash.is_some() {
+        problems.push(String::from(
+            "`user_name` and `password_hash` must be set together",
+        ));
+    }
+    if config.client_ca_path.is_some() && !tls {
+        problems.push(String::from(
+            "`client_ca_path` needs `cert_path` and `key_path`, client certificates are only verified over HTTPS",
+        ));
+    }
+    if config.tls_alpn.is_empty() {
+        problems.push(String::from(
+            "`tls_alpn` needs at least one of \"h2\" and \"http/1.1\"",
+        ));
+    }
+    if let Some(port) = config.http_redirect_port {
+        if !tls {
+            problems.push(String::from(
+                "`http_redirect_port` needs `cert_path` and `key_path`, there is no HTTPS to redirect to",
+            ));
         }
-        if let Some(port) = toml_cfg.port {
-            config.port = port;
+        if port == config.port && config.listen.is_empty() {
+            problems.push(String::from("`http_redirect_port` must differ from `port`"));
         }
-        if let Some(dir) = toml_cfg.root_dir {
-            config.root_dir = dir;
+        if tls && !config.listen.is_empty() && !config.listen.iter().any(|entry| entry.tls(tls)) {
+            problems.push(String::from(
+                "`http_redirect_port` needs a `listen` entry with HTTPS to redirect to",
+            ));
         }
-        if let Some(workers) = toml_cfg.workers {
-            config.workers = workers;
+    }
+    for (index, entry) in config.listen.iter().enumerate() {
+        let prefix = format!("listen entry {}", index + 1);
+        match (entry.port, &entry.path) {
+            (Some(port), None) => {
+                if port == 0 {
+                    problems.push(format!("{}: `port` must not be 0", prefix));
+                }
+                if config.http_redirect_port == Some(port) {
+                    problems.push(format!(
+                        "{}: `port` must differ from `http_redirect_port`",
+                        prefix
+                    ));
+                }
+                if entry.tls == Some(true) && !tls {
+                    problems.push(format!(
+                        "{}: `tls` needs `cert_path` and `key_path`",
+                        prefix
+                    ));
+                }
+                if entry.mode.is_some() {
+                    problems.push(format!("{}: `mode` only applies to Unix sockets", prefix));
+                }
+            }
+            (None, Some(_)) => {
+                if entry.address.is_some() {
+                    problems.push(format!(
+                        "{}: `address` does not apply to Unix sockets",
+                        prefix
+                    ));
+                }
+                if entry.tls == Some(true) {
+                    problems.push(format!("{}: TLS is not supported on Unix sockets", prefix));
+                }
+            }
+            _ => problems.push(format!(
+                "{}: exactly one of `port` and `path` is needed",
+                prefix
+            )),
+        }
+    }
+    for vhost in &config.vhosts {
+        if vhost.cert_path.is_some() != vhost.key_path.is_some() {
+            problems.push(format!(
+                "vhost {:?}: `cert_path` and `key_path` must be set together",
+                vhost.hosts
+            ));
         }
-        config.cert_path = toml_cfg.cert_path;
-        config.key_path = toml_cfg.key_path;
-        config.user_name = toml_cfg.user_name;
-        config.password_hash = toml_cfg.password_hash;
+        if vhost.cert_path.is_some() && !tls {
+            problems.push(format!(
+                "vhost {:?}: a vhost certificate needs the top level `cert_path` and `key_path`",
+                vhost.hosts
+            ));
+        }
+    }
+    problems
+}
+
+/// The effective settings, with the problems found in them such as settings needing each other.
+pub(crate) fn config_with_problems(cli: Cli) -> Result<(Config, Vec<String>)> {
+    let (settings, _) = layered_settings(&cli)?;
+    let mut config: Config = settings.try_into()?;
+    config.cli = cli;
+    let problems = check_settings(&config);
+    Ok((config, problems))
+}
+
+pub fn get_config(cli: Cli) -> Result<Config> {
+    let (config, problems) = config_with_problems(cli)?;
+    if !problems.is_empty() {
+        return Err(anyhow!(problems.join("\n")));
     }
 
     Ok(config)
@@ -120,11 +1041,13 @@ pub fn get_config(cli: Cli) -> Result<Config> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{AppState, AuthInfo, app_config, basic_auth};
+    use crate::auth::{AuthInfo, authenticate};
+    use crate::test_util::TempDir;
+    use crate::{AppState, app_config};
     use ::base64::Engine;
     use actix_http::StatusCode;
-    use actix_web::{App, middleware::Condition, test, web};
-    use actix_web_httpauth::middleware::HttpAuthentication;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, middleware::from_fn, web};
     use base64::engine::general_purpose as base64;
     use std::time::Instant;
 
@@ -133,10 +1056,10 @@ mod tests {
     }
 
     #[test]
-    async fn test_cli() {
+    fn test_cli() {
         let cli = Cli::parse_from(Vec::<String>::new());
-        assert_eq!(cli.port, 1131);
-        assert_eq!(cli.root_dir, PathBuf::from("."));
+        assert_eq!(cli.settings.port, None);
+        assert_eq!(cli.settings.root_dir, None);
         assert_eq!(cli.config_file, None);
         assert_eq!(cli.hash_password, None);
         let cli = Cli::parse_from(args_to_vec(&[
@@ -152,15 +1075,203 @@ mod tests {
             "--hash-password",
             "mypassword",
         ]));
-        assert_eq!(cli.address, String::from("123.123.123.123"));
-        assert_eq!(cli.port, 1139);
-        assert_eq!(cli.root_dir, PathBuf::from("./目录"));
+        assert_eq!(cli.settings.address, Some(String::from("123.123.123.123")));
+        assert_eq!(cli.settings.port, Some(1139));
+        assert_eq!(cli.settings.root_dir, Some(PathBuf::from("./目录")));
         assert_eq!(cli.config_file, Some(PathBuf::from("配置文件.toml")));
         assert_eq!(cli.hash_password, Some(String::from("mypassword")));
+        assert_eq!(cli.command, None);
+        let cli = Cli::parse_from(args_to_vec(&[
+            "my-cont-srv",
+            "user",
+            "-f",
+            "users",
+            "add",
+            "alice",
+            "--scheme",
+            "argon2",
+        ]));
+        assert_eq!(
+            cli.command,
+            Some(Command::User {
+                file: Some(PathBuf::from("users")),
+                action: UserAction::Add {
+                    name: String::from("alice"),
+                    password: None,
+                    scheme: HashScheme::Argon2
+                }
+            })
+        );
+        let cli = Cli::parse_from(args_to_vec(&[
+            "my-cont-srv",
+            "share",
+            "/books/a.epub",
+            "--max-downloads",
+            "3",
+        ]));
+        assert_eq!(
+            cli.command,
+            Some(Command::Share {
+                path: String::from("/books/a.epub"),
+                host: None,
+                expires_in: DEFAULT_SHARE_EXPIRES_IN,
+                max_downloads: Some(3),
+                password: None,
+                base_url: String::new(),
+            })
+        );
+        let cli = Cli::parse_from(args_to_vec(&[
+            "my-cont-srv",
+            "gen-cert",
+            "--san",
+            "localhost",
+            "--san",
+            "127.0.0.1",
+        ]));
+        assert_eq!(
+            cli.command,
+            Some(Command::GenCert(GenCertArgs {
+                sans: vec![String::from("localhost"), String::from("127.0.0.1")],
+                days: 365,
+                ca_cert: None,
+                ca_key: None,
+                cert_out: PathBuf::from("cert.pem"),
+                key_out: PathBuf::from("key.pem"),
+                force: false,
+            }))
+        );
+        let cli = Cli::try_parse_from(args_to_vec(&[
+            "my-cont-srv",
+            "gen-cert",
+            "--san",
+            "localhost",
+            "--ca-cert",
+            "ca.pem",
+        ]));
+        assert!(cli.is_err());
+        // every setting is a flag, taking a TOML value where a string would not do
+        let cli = Cli::parse_from(args_to_vec(&[
+            "my-cont-srv",
+            "--form-login",
+            "--watch-files=false",
+            "--tls-min-version",
+            "1.3",
+            "--tls-alpn",
+            r#"["http/1.1"]"#,
+            "--acl",
+            r#"[{ path = "/", users = ["*"], perms = ["read"] }]"#,
+            "--user-name",
+            "1234",
+            "user",
+            "remove",
+            "bob",
+        ]));
+        assert_eq!(cli.settings.form_login, Some(true));
+        assert_eq!(cli.settings.watch_files, Some(false));
+        assert_eq!(cli.settings.tls_min_version, Some(TlsVersion::Tls13));
+        assert_eq!(cli.settings.tls_alpn, Some(vec![AlpnProtocol::Http11]));
+        assert_eq!(cli.settings.acl.unwrap()[0].perms, vec![Perm::Read]);
+        assert_eq!(cli.settings.user_name.as_deref(), Some("1234"));
+        assert!(cli.command.is_some());
+        let cli = Cli::try_parse_from(args_to_vec(&["my-cont-srv", "--port", "many"]));
+        assert!(cli.is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        // flags win over the config file, which wins over the defaults
+        let cli = parse_cli_from(args_to_vec(&[
+            "my-cont-srv",
+            "-c",
+            "res_dir/config.toml",
+            "-p",
+            "1139",
+            "--workers",
+            "8",
+        ]));
+        let (_, sources) = layered_settings(&cli).unwrap();
+        assert_eq!(sources["port"], Source::CommandLine);
+        assert_eq!(sources["address"], Source::File);
+        assert!(!sources.contains_key("templates_dir"));
+        let cfg = get_config(cli).unwrap();
+        assert_eq!(cfg.port, 1139);
+        assert_eq!(cfg.workers, 8);
+        assert_eq!(cfg.address, "127.0.0.1");
+        assert_eq!(cfg.epub_concurrency, 2);
+        assert_eq!(cfg.templates_dir, None);
+
+        let cli = parse_cli_from(args_to_vec(&["my-cont-srv"]));
+        let (_, sources) = layered_settings(&cli).unwrap();
+        assert_eq!(sources["port"], Source::Default);
+        let cfg = get_config(cli).unwrap();
+        assert_eq!(cfg.address, DEFAULT_ADDRESS);
+        assert_eq!(cfg.port, DEFAULT_PORT);
+        assert_eq!(cfg.root_dir, PathBuf::from(DEFAULT_ROOT_DIR));
+        assert_eq!(cfg.workers, DEFAULT_WORKERS);
+        assert!(cfg.acl.is_empty());
+        // the pairs are checked whatever layer their halves come from
+        let cli = parse_cli_from(args_to_vec(&["my-cont-srv", "--cert-path", "cert.pem"]));
+        assert!(get_config(cli).is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let settings: toml::Table = toml::from_str(
+            r#"
+            share_secret = "secret"
+            password_hash = "$2b$04$hash"
+            users = [{ name = "alice", password_hash = "$2b$04$alice" }]
+            vhost = [{ hosts = ["a.example"], users = [{ name = "bob", password_hash = "$2b$04$bob" }] }]
+            "#,
+        )
+        .unwrap();
+        let printed = settings
+            .into_iter()
+            .map(|(key, value)| format!("{} = {}", key, redact(&key, value)))
+            .collect::<Vec<_>>();
+        assert!(printed.iter().all(|line| !line.contains("$2b$")));
+        assert!(printed.contains(&String::from(r#"share_secret = "<redacted>""#)));
+        assert!(printed.contains(&String::from(
+            r#"users = [{ name = "alice", password_hash = "<redacted>" }]"#
+        )));
+        assert!(printed.iter().any(|line| line.contains(r#"name = "bob""#)));
     }
 
     #[test]
-    async fn test_config() {
+    fn test_config_errors() {
+        let dir = TempDir::new("cfg");
+        let file = dir.join("config.toml");
+        let cli = Cli::parse_from(args_to_vec(&["my-cont-srv", "-c", file.to_str().unwrap()]));
+        for (toml, error) in [
+            ("port = 1\nprot = 2\n", ":2:1: unknown field `prot`"),
+            (
+                "[[acl]]\npath = \"/\"\nusers = []\nperms = [\"read\"]\n  user = 1\n",
+                ":5:3: unknown field `user`",
+            ),
+            (
+                "port = \"x\"\n",
+                ":1:8: invalid type: string \"x\", expected u16",
+            ),
+            // columns count characters rather than bytes
+            ("user_name = \"Zoë\" port = 1\n", ":1:19: unexpected key"),
+        ] {
+            std::fs::write(&file, toml).unwrap();
+            let err = get_config(cli.clone()).err().unwrap().to_string();
+            assert!(err.starts_with(file.to_str().unwrap()), "{}", err);
+            assert!(err.contains(error), "{}", err);
+        }
+        // all problems are reported at once
+        std::fs::write(
+            &file,
+            "port = 1131\nhttp_redirect_port = 1131\nuser_name = \"alice\"\ntls_alpn = []\n",
+        )
+        .unwrap();
+        let err = get_config(cli).err().unwrap().to_string();
+        assert_eq!(err.lines().count(), 4, "{}", err);
+    }
+
+    #[test]
+    fn test_config() {
         let cli = Cli::parse_from(args_to_vec(&["my-cont-srv", "-c", "res_dir/config.toml"]));
         let cfg = get_config(cli);
         assert!(cfg.is_ok());
@@ -178,65 +1289,156 @@ mod tests {
             ))
         );
         assert_eq!(cfg.workers, 3usize);
+        assert_eq!(cfg.symlink_policy, SymlinkPolicy::FollowWithinRoot);
+        assert_eq!(cfg.templates_dir, None);
+        assert_eq!(cfg.epub_concurrency, 2);
+        assert_eq!(cfg.epub_timeout, 20);
+        assert!(cfg.watch_files);
+        assert_eq!(cfg.epub_toc_cache_bytes, 2097152);
+        assert_eq!(cfg.epub_cont_cache_bytes, 33554432);
+        assert_eq!(cfg.cache_max_entry_bytes, 4194304);
+        assert_eq!(cfg.epub_doc_cache_entries, 5);
+        assert!(cfg.cache_stats);
+        assert_eq!(
+            cfg.users,
+            vec![UserEntry {
+                name: String::from("guest"),
+                password_hash: String::from(
+                    "$2b$12$RIHLGy9BQSOw5R.ZaMtMme9jfsHVo8vnXcjeRN9NOtPqRhJEJT8Bm"
+                ),
+            }]
+        );
+        assert_eq!(cfg.users_file, Some(PathBuf::from("res_dir/users")));
+        assert!(cfg.form_login);
+        assert_eq!(cfg.session_idle_timeout, 3600);
+        assert_eq!(cfg.session_remember_days, 7);
+        assert_eq!(cfg.auth_max_failures, 10);
+        assert_eq!(cfg.auth_lockout, 30);
+        assert_eq!(cfg.auth_max_lockout, 1800);
+        assert_eq!(cfg.auth_verify_concurrency, 1);
+        assert_eq!(cfg.share_secret.as_deref(), Some("change-me-share-secret"));
+        assert_eq!(
+            cfg.client_ca_path,
+            Some(PathBuf::from("res_dir/client_ca.pem"))
+        );
+        assert_eq!(cfg.client_auth, ClientAuth::Optional);
+        assert_eq!(cfg.client_cert_user, CertUserField::CommonName);
+        assert_eq!(cfg.tls_min_version, TlsVersion::Tls12);
+        assert_eq!(
+            cfg.tls_cipher_suites,
+            vec![
+                String::from("TLS13_AES_256_GCM_SHA384"),
+                String::from("TLS13_AES_128_GCM_SHA256"),
+                String::from("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"),
+                String::from("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"),
+            ]
+        );
+        assert_eq!(cfg.tls_alpn, vec![AlpnProtocol::H2, AlpnProtocol::Http11]);
+        assert!(cfg.tls_session_tickets);
+        assert_eq!(cfg.tls_session_cache, 512);
+        assert_eq!(cfg.hsts_max_age, 31536000);
+        assert!(!cfg.hsts_include_subdomains);
+        assert_eq!(cfg.http_redirect_port, Some(11311));
+        assert!(!cfg.watch_config);
+        assert_eq!(cfg.shutdown_timeout, 10);
+        assert_eq!(
+            cfg.groups,
+            HashMap::from([(
+                String::from("readers"),
+                vec![String::from("reader"), String::from("guest")]
+            )])
+        );
+        assert_eq!(
+            cfg.acl,
+            vec![
+                AclRule {
+                    path: String::from("/images"),
+                    users: vec![String::from("anonymous")],
+                    perms: vec![Perm::Read, Perm::List],
+                },
+                AclRule {
+                    path: String::from("/**/*.epub"),
+                    users: vec![String::from("@readers"), String::from("myuser")],
+                    perms: vec![Perm::Read],
+                },
+            ]
+        );
+        assert_eq!(
+            cfg.vhosts,
+            vec![VhostEntry {
+                hosts: vec![String::from("books.localhost")],
+                cert_path: Some(PathBuf::from("res_dir/vhost_cert.pem")),
+                key_path: Some(PathBuf::from("res_dir/vhost_key.pem")),
+                root_dir: PathBuf::from("res_dir/images"),
+                users: None,
+                users_file: None,
+                groups: None,
+                acl: Some(vec![AclRule {
+                    path: String::from("/"),
+                    users: vec![String::from("anonymous")],
+                    perms: vec![Perm::Read, Perm::List],
+                }]),
+            }]
+        );
     }
 
     #[actix_web::test]
     async fn test_auth() {
         let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
-        let auth_info = AuthInfo::new(
-            "myuser",
-            "$2b$12$iNwN4yF3d9AUXBOexcfpDuBG2GH25Wmz9XGPf5q73Dio5cK6GHvWi",
-        );
-        let app = test::init_service(
+        let auth_info = AuthInfo::new(HashMap::from([(
+            String::from("myuser"),
+            String::from("$2b$12$iNwN4yF3d9AUXBOexcfpDuBG2GH25Wmz9XGPf5q73Dio5cK6GHvWi"),
+        )]));
+        let app = init_service(
             App::new()
                 .configure(app_config)
                 .app_data(app_data)
                 .app_data(auth_info)
-                .wrap(Condition::new(true, HttpAuthentication::basic(basic_auth))),
+                .wrap(from_fn(authenticate)),
         )
         .await;
         // no auth info
-        let req = test::TestRequest::default().to_request();
-        let resp = test::call_service(&app, req).await;
+        let req = TestRequest::default().to_request();
+        let resp = call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
         // wrong user name
-        let req = test::TestRequest::default()
+        let req = TestRequest::default()
             .append_header((
                 "Authorization",
                 format!("Basic {}", base64::STANDARD.encode("dummy:mypassword")),
             ))
             .to_request();
-        let resp = test::call_service(&app, req).await;
+        let resp = call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
         // wrong password
-        let req = test::TestRequest::default()
+        let req = TestRequest::default()
             .append_header((
                 "Authorization",
                 format!("Basic {}", base64::STANDARD.encode("myuser:dummy")),
             ))
             .to_request();
-        let resp = test::call_service(&app, req).await;
+        let resp = call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
         // correct auth info
-        let req = test::TestRequest::default()
+        let req = TestRequest::default()
             .append_header((
                 "Authorization",
                 format!("Basic {}", base64::STANDARD.encode("myuser:mypassword")),
             ))
             .to_request();
         let start = Instant::now();
-        let resp = test::call_service(&app, req).await;
+        let resp = call_service(&app, req).await;
         let duration1 = start.elapsed().as_nanos();
         assert_eq!(resp.status(), StatusCode::OK);
         // 2nd time with correct auth info, to check cache is working
-        let req = test::TestRequest::default()
+        let req = TestRequest::default()
             .append_header((
                 "Authorization",
                 format!("Basic {}", base64::STANDARD.encode("myuser:mypassword")),
             ))
             .to_request();
         let start = Instant::now();
-        let resp = test::call_service(&app, req).await;
+        let resp = call_service(&app, req).await;
         let duration2 = start.elapsed().as_nanos();
         assert_eq!(resp.status(), StatusCode::OK);
         assert!(duration2 < duration1 / 10);
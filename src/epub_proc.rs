@@ -1,14 +1,135 @@
-use crate::AppState;
+use crate::cache::{self, ByteSize, Cached, FileStamp};
+use crate::config::Perm;
+use crate::{AppState, acl, path_res, share};
 use ::base64::Engine;
-use actix_web::{HttpResponse, Responder, web};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
 use base64::engine::general_purpose as base64;
 use epub::doc::{EpubDoc, NavPoint};
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock, PoisonError};
 use std::{
-    io::{Read, Seek},
+    fs::File,
+    io::{BufReader, Read, Seek},
     path::PathBuf,
 };
 
+pub type SharedDoc = Arc<std::sync::Mutex<EpubDoc<BufReader<File>>>>;
+
+/// An opened epub holds little besides its parsed OPF, so the doc cache is bounded by its entry
+/// count only.
+impl ByteSize for SharedDoc {
+    fn byte_size(&self) -> usize {
+        0
+    }
+}
+/// MIME type and contents of a resource inside an epub.
+pub type EpubCont = (String, Vec<u8>);
+
+/// Identifies an opened epub file, a replaced file gets a different key.
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub struct DocKey {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+}
+
+/// Runs blocking epub work (file reading, zip inflating, parsing) on the blocking thread pool, so
+/// the async workers stay responsive. At most `epub_concurrency` jobs run at the same time, a
+/// request arriving when all slots are busy or waiting longer than `epub_timeout` gets a 503.
+async fn run_blocking<T, F>(app_state: &AppState, f: F) -> Result<T, HttpResponse>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let unavailable = |msg: &str| {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, "1"))
+            .body(String::from(msg))
+    };
+    let Ok(permit) = app_state.epub_permits.clone().try_acquire_owned() else {
+        return Err(unavailable("Too many epub requests in progress"));
+    };
+    // the permit is moved into the job, so a timed out job still holds its slot until it ends
+    let job = web::block(move || {
+        let result = f();
+        drop(permit);
+        result
+    });
+    match tokio::time::timeout(app_state.epub_timeout, job).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(err)) => {
+            Err(HttpResponse::InternalServerError().body(format!("Epub job failed: {:?}", err)))
+        }
+        Err(_) => Err(unavailable("Epub request timed out")),
+    }
+}
+
+/// Resolves the epub file path relative to the contents root and checks that the requester may
+/// read the file it leads to, returning its absolute path and current stamp.
+async fn resolve_epub(
+    req: &HttpRequest,
+    app_state: &AppState,
+    rel_path: &str,
+) -> Result<(PathBuf, FileStamp), HttpResponse> {
+    let path = path_res::resolve(&app_state.root_dir, rel_path, app_state.symlink_policy)
+        .await
+        .map_err(|err| err.to_response())?;
+    acl::authorize_resolved(
+        req,
+        &app_state.acl,
+        &app_state.root_dir,
+        &path,
+        rel_path,
+        &[Perm::Read],
+    )
+    .await?;
+    let stamp = FileStamp::of(&path).await.map_err(|err| {
+        HttpResponse::InternalServerError().body(format!(
+            "Reading epub [{:?}] metadata failed: {:?}",
+            &path, err
+        ))
+    })?;
+    Ok((cache::source_path(&path), stamp))
+}
+
+/// Returns the parsed epub at `path`, parsing it only when it is not in the document cache.
+async fn open_doc(
+    app_state: &AppState,
+    path: &PathBuf,
+    stamp: FileStamp,
+) -> Result<SharedDoc, HttpResponse> {
+    let parse_failed = |err: &dyn std::fmt::Debug| {
+        HttpResponse::InternalServerError()
+            .content_type("text/html; charset=utf-8")
+            .body(format!(
+                "Reading/Parsing epub [{:?}] failed: {:?}",
+                path, err
+            ))
+    };
+    let key = DocKey {
+        path: path.clone(),
+        stamp,
+    };
+    {
+        let mut cache = app_state.epub_doc_cache.lock().await;
+        if let Some(doc) = cache.get(&key) {
+            return Ok(doc.clone());
+        }
+    }
+
+    let doc_path = path.clone();
+    let doc = run_blocking(app_state, move || {
+        EpubDoc::new(&doc_path).map_err(|err| format!("{:?}", err))
+    })
+    .await?
+    .map_err(|err| parse_failed(&err))?;
+    let doc = Arc::new(std::sync::Mutex::new(doc));
+    {
+        let mut cache = app_state.epub_doc_cache.lock().await;
+        cache.put(key, doc.clone());
+    }
+    Ok(doc)
+}
+
 fn resp_navpoint(out: &mut String, level: u8, nav: &NavPoint) {
     out.push_str("<div>");
     for _ in 0..level {
@@ -22,68 +143,81 @@ fn resp_navpoint(out: &mut String, level: u8, nav: &NavPoint) {
     }
 }
 
-pub async fn epub_toc(req_path: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+pub async fn epub_toc(
+    req: HttpRequest,
+    req_path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
     let path = req_path.into_inner();
+    if let Err(resp) = acl::authorize(&req, &app_state.acl, &path, &[Perm::Read]) {
+        return resp;
+    }
+
+    let (file_path, stamp) = match resolve_epub(&req, &app_state, &path).await {
+        Ok(res) => res,
+        Err(resp) => return resp,
+    };
 
-    let mut out = String::new();
-    let mut cached = false;
     {
         let mut cache = app_state.epub_toc_cache.lock().await;
-        if cache.contains(&path) {
-            out = cache.get(&path).unwrap().to_owned();
-            cached = true;
+        if let Some(out) = cache.get_fresh(&path, &stamp) {
+            return HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(out.clone());
         }
     }
-    if cached {
-        return HttpResponse::Ok()
-            .content_type("text/html; charset=utf-8")
-            .body(out);
-    }
 
-    let mut file_path = app_state.root_dir.clone();
-    file_path.push(&path);
-    let doc = EpubDoc::new(&file_path);
-    if doc.is_err() {
-        return HttpResponse::InternalServerError()
-            .content_type("text/html; charset=utf-8")
-            .body(format!(
-                "Reading/Parsing epub [{:?}] failed: {:?}",
-                &path,
-                doc.err().unwrap()
-            ));
-    }
-    let doc = doc.unwrap();
+    let doc = match open_doc(&app_state, &file_path, stamp).await {
+        Ok(doc) => doc,
+        Err(resp) => return resp,
+    };
 
     let b64_path = base64::URL_SAFE_NO_PAD.encode(&path);
 
-    if doc.toc.is_empty() {
-        if !doc.spine.is_empty()
-            && let Some(res_item) = doc.resources.get(&doc.spine[0].idref)
-        {
-            return epub_cont_proc(
-                b64_path,
-                res_item.path.to_string_lossy().to_string(),
-                app_state,
-            )
-            .await;
+    let toc_b64_path = b64_path.clone();
+    let toc = run_blocking(&app_state, move || {
+        let doc = doc.lock().unwrap_or_else(PoisonError::into_inner);
+        if doc.toc.is_empty() {
+            let first_page = doc
+                .spine
+                .first()
+                .and_then(|item| doc.resources.get(&item.idref))
+                .map(|res_item| res_item.path.to_string_lossy().to_string());
+            return Err(first_page);
         }
-        return HttpResponse::NotFound().body("No contents found in the epub file");
-    }
-
-    out.push_str(&format!(
-        r#"<head><base href="/epub_cont/{}/"/></head>"#,
-        b64_path
-    ));
-
-    out.push_str("<body>");
-    for item in &doc.toc {
-        resp_navpoint(&mut out, 0, item);
-    }
-    out.push_str("</body>");
+        let mut out = format!(
+            r#"<head><base href="/epub_cont/{}/"/></head>"#,
+            toc_b64_path
+        );
+        out.push_str("<body>");
+        for item in &doc.toc {
+            resp_navpoint(&mut out, 0, item);
+        }
+        out.push_str("</body>");
+        Ok(out)
+    })
+    .await;
+    let out = match toc {
+        Ok(Ok(toc)) => toc,
+        Ok(Err(Some(first_page))) => {
+            return epub_cont_proc(&req, b64_path, first_page, app_state).await;
+        }
+        Ok(Err(None)) => {
+            return HttpResponse::NotFound().body("No contents found in the epub file");
+        }
+        Err(resp) => return resp,
+    };
 
     {
         let mut cache = app_state.epub_toc_cache.lock().await;
-        cache.put(path, out.clone());
+        cache.put(
+            path,
+            Cached {
+                source: file_path,
+                stamp,
+                value: out.clone(),
+            },
+        );
     }
 
     HttpResponse::Ok()
@@ -158,25 +292,12 @@ fn epub_gen_html_nav_elem<R: Read + Seek>(
 }
 
 async fn epub_cont_proc(
+    req: &HttpRequest,
     file_path: String,
     inner_path: String,
     app_state: web::Data<AppState>,
 ) -> HttpResponse {
     let whole_path = format!("{}/{}", file_path, inner_path);
-    let (mut mime, mut cont) = (String::new(), Vec::<u8>::new());
-
-    let mut cached = false;
-    {
-        let mut cache = app_state.epub_cont_cache.lock().await;
-        if cache.contains(&whole_path) {
-            (mime, cont) = cache.get(&whole_path).unwrap().to_owned();
-            cached = true;
-        }
-    }
-    if cached {
-        return resp_epub_cont(mime, cont);
-    }
-
     let path = base64::URL_SAFE_NO_PAD.decode(&file_path);
     if path.is_err() {
         return HttpResponse::BadRequest().body(format!(
@@ -187,60 +308,96 @@ async fn epub_cont_proc(
     }
     let path = path.unwrap();
     let path_str = String::from_utf8_lossy(&path);
-
-    let mut path_buf = app_state.root_dir.clone();
-    path_buf.push(&*path_str);
-    let doc = EpubDoc::new(&path_buf);
-    if doc.is_err() {
-        return HttpResponse::InternalServerError()
-            .content_type("text/html; charset=utf-8")
-            .body(format!(
-                "Reading/Parsing epub [{:?}] failed: {:?}",
-                &path,
-                doc.err().unwrap()
-            ));
+    if let Err(resp) = acl::authorize(req, &app_state.acl, &path_str, &[Perm::Read]) {
+        return resp;
     }
-    let mut doc = doc.unwrap();
+    let read_cookie = match share::limit_epub_read(req, &app_state, &path_str, &file_path) {
+        Ok(cookie) => cookie,
+        Err(resp) => return resp,
+    };
+    let resp_epub_cont = |mime, cont| {
+        let mut resp = resp_epub_cont(mime, cont);
+        if let Some(cookie) = &read_cookie {
+            let _ = resp.add_cookie(cookie);
+        }
+        resp
+    };
 
-    let cont_res = doc.get_resource_by_path(&inner_path);
-    if cont_res.is_none() {
-        return HttpResponse::NotFound().body(format!("Resource [{}] not found", inner_path));
-    }
-    let mut cont = cont_res.unwrap();
-    if let Some(mime_str) = doc.get_resource_mime_by_path(&inner_path) {
-        mime = mime_str;
-    }
-    if mime.is_empty() && inner_path.contains("htm") {
-        mime = String::from("text/html; charset=utf-8");
-    }
+    let (path_buf, stamp) = match resolve_epub(req, &app_state, &path_str).await {
+        Ok(res) => res,
+        Err(resp) => return resp,
+    };
 
-    if mime.contains("htm")
-        && let Some(nav) = epub_gen_html_nav_elem(&doc, &file_path, &path_str, &inner_path)
     {
-        static RE: LazyLock<regex::Regex> =
-            LazyLock::new(|| regex::Regex::new("<body.*?>").unwrap());
-        let cont_str = String::from_utf8_lossy(&cont);
-        cont = RE
-            .replace_all(&cont_str, &format!(r#"$0{}"#, &nav))
-            .replace("</body>", &format!("{}</body>", &nav))
-            .as_bytes()
-            .to_vec();
+        let mut cache = app_state.epub_cont_cache.lock().await;
+        if let Some((mime, cont)) = cache.get_fresh(&whole_path, &stamp) {
+            return resp_epub_cont(mime.clone(), cont.clone());
+        }
     }
 
+    let doc = match open_doc(&app_state, &path_buf, stamp).await {
+        Ok(doc) => doc,
+        Err(resp) => return resp,
+    };
+
+    let (b64_path, file_path, job_inner_path) =
+        (file_path, path_str.to_string(), inner_path.clone());
+    let res = run_blocking(&app_state, move || {
+        let inner_path = job_inner_path;
+        let mut doc = doc.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut cont = doc.get_resource_by_path(&inner_path)?;
+        let mut mime = doc
+            .get_resource_mime_by_path(&inner_path)
+            .unwrap_or_default();
+        if mime.is_empty() && inner_path.contains("htm") {
+            mime = String::from("text/html; charset=utf-8");
+        }
+
+        if mime.contains("htm")
+            && let Some(nav) = epub_gen_html_nav_elem(&doc, &b64_path, &file_path, &inner_path)
+        {
+            static RE: LazyLock<regex::Regex> =
+                LazyLock::new(|| regex::Regex::new("<body.*?>").unwrap());
+            let cont_str = String::from_utf8_lossy(&cont);
+            cont = RE
+                .replace_all(&cont_str, &format!(r#"$0{}"#, &nav))
+                .replace("</body>", &format!("{}</body>", &nav))
+                .as_bytes()
+                .to_vec();
+        }
+        Some((mime, cont))
+    })
+    .await;
+    let (mime, cont) = match res {
+        Ok(Some(res)) => res,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("Resource [{}] not found", inner_path));
+        }
+        Err(resp) => return resp,
+    };
+
     {
         let mut cache = app_state.epub_cont_cache.lock().await;
-        cache.put(whole_path, (mime.clone(), cont.clone()));
+        cache.put(
+            whole_path,
+            Cached {
+                source: path_buf,
+                stamp,
+                value: (mime.clone(), cont.clone()),
+            },
+        );
     }
 
     resp_epub_cont(mime, cont)
 }
 
 pub async fn epub_cont(
+    req: HttpRequest,
     req_path: web::Path<(String, String)>,
     app_state: web::Data<AppState>,
 ) -> impl Responder {
     let (file_path, inner_path) = req_path.into_inner();
-    epub_cont_proc(file_path, inner_path, app_state).await
+    epub_cont_proc(&req, file_path, inner_path, app_state).await
 }
 
 #[cfg(test)]
@@ -249,7 +406,9 @@ mod tests {
     use crate::{AppState, app_config};
     use actix_http::StatusCode;
     use actix_web::{App, test};
-    use std::time::Instant;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use tokio::sync::Semaphore;
 
     #[actix_web::test]
     async fn test_epub_toc_v2() {
@@ -339,6 +498,92 @@ mod tests {
         assert!(duration2 < duration1 / 10);
     }
 
+    #[actix_web::test]
+    async fn test_epub_doc_cache() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app =
+            test::init_service(App::new().configure(app_config).app_data(app_data.clone())).await;
+        let req = test::TestRequest::default()
+            .uri("/epub_cont/cmVzX2Rpci92My5lcHVi/EPUB/xhtml/epub30-titlepage.xhtml")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // other resources of the same book reuse the parsed container
+        for inner_path in [
+            "EPUB/xhtml/epub30-nav.xhtml",
+            "EPUB/xhtml/epub30-terminology.xhtml",
+            "EPUB/img/idpflogo_web_125.jpg",
+        ] {
+            let req = test::TestRequest::default()
+                .uri(&format!("/epub_cont/cmVzX2Rpci92My5lcHVi/{}", inner_path))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", inner_path);
+        }
+        let req = test::TestRequest::default()
+            .uri("/epub_cont/cmVzX2Rpci92My5lcHVi/EPUB/css/epub-spec.css")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stats = app_data.epub_doc_cache.lock().await.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 4, 1));
+        assert_eq!(app_data.epub_cont_cache.lock().await.len(), 5);
+    }
+
+    #[actix_web::test]
+    async fn test_epub_doc_poisoned() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app =
+            test::init_service(App::new().configure(app_config).app_data(app_data.clone())).await;
+        let epub = Path::new("./res_dir/v3.epub");
+        let stamp = FileStamp::of(epub).await.unwrap();
+        let doc = open_doc(&app_data, &cache::source_path(epub), stamp)
+            .await
+            .unwrap();
+        // a job panicking while it holds the cached document does not break the book
+        std::thread::spawn(move || {
+            let _doc = doc.lock().unwrap();
+            panic!("poisoning the document");
+        })
+        .join()
+        .unwrap_err();
+        for inner_path in ["EPUB/xhtml/epub30-nav.xhtml", "EPUB/css/epub-spec.css"] {
+            let req = test::TestRequest::default()
+                .uri(&format!("/epub_cont/cmVzX2Rpci92My5lcHVi/{}", inner_path))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", inner_path);
+        }
+        assert_eq!(app_data.epub_doc_cache.lock().await.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_epub_saturated() {
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.epub_permits = Arc::new(Semaphore::new(0));
+        let app_data = web::Data::new(app_state);
+        let app = test::init_service(App::new().configure(app_config).app_data(app_data)).await;
+        let req = test::TestRequest::default()
+            .uri("/epub_toc/res_dir/v2.epub")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.epub_timeout = Duration::from_millis(10);
+        let res = run_blocking(&app_state, || {
+            std::thread::sleep(Duration::from_millis(200))
+        })
+        .await;
+        assert_eq!(res.err().unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        // the timed out job keeps its slot until it really ends
+        assert_eq!(
+            app_state.epub_permits.available_permits(),
+            crate::config::DEFAULT_EPUB_CONCURRENCY - 1
+        );
+    }
+
     #[actix_web::test]
     async fn test_epub_cont_v3_first() {
         let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
@@ -404,6 +649,6 @@ mod tests {
             .uri("/epub_toc/non_exist")
             .to_request();
         let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 }
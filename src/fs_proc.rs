@@ -1,9 +1,32 @@
-use crate::AppState;
-use actix_web::{HttpRequest, HttpResponse, web};
-use std::path::PathBuf;
+use crate::auth::Identity;
+use crate::config::Perm;
+use crate::list_query::{ListQuery, SortKey, SortOrder, natural_cmp};
+use crate::{AppState, acl, path_res, share};
+use actix_web::http::Method;
+use actix_web::http::header::{
+    ACCEPT_RANGES, ContentEncoding, ContentRange, ContentRangeSpec, ETag, EntityTag, HttpDate,
+    IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range, VARY,
+};
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{Stream, StreamExt, future, stream};
+use glob::MatchOptions;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
 use tokio_util::io::ReaderStream;
 
+type BodyStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>>>>;
+
+const MAX_RANGES: usize = 32;
+
 fn format_size(size: u64) -> String {
     const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
     let mut size = size as f64;
@@ -17,8 +40,199 @@ fn format_size(size: u64) -> String {
     format!("{:.2} {}", size, UNITS[unit_index])
 }
 
-async fn dir_get(req: &HttpRequest, path: &PathBuf) -> HttpResponse {
-    let mut out = String::from("");
+#[derive(Serialize)]
+struct DirEntryInfo {
+    name: String,
+    url: String,
+    is_dir: bool,
+    size: Option<u64>,
+    size_str: Option<String>,
+    mtime: Option<String>,
+    epub_url: Option<String>,
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+}
+
+impl DirEntryInfo {
+    fn ext(&self) -> String {
+        if self.is_dir {
+            return String::new();
+        }
+        Path::new(&self.name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+struct SortUrls {
+    name: String,
+    size: String,
+    mtime: String,
+    #[serde(rename = "type")]
+    type_: String,
+}
+
+#[derive(Serialize)]
+struct Crumb {
+    name: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct DirListCtx<'a> {
+    path: &'a str,
+    breadcrumbs: Vec<Crumb>,
+    parent_url: Option<String>,
+    entries: Vec<DirEntryInfo>,
+    sort: SortKey,
+    order: SortOrder,
+    dirs_first: bool,
+    filter: Option<&'a str>,
+    sort_urls: SortUrls,
+    dirs_first_url: String,
+    view_params: Vec<(&'static str, String)>,
+    total: usize,
+    page: usize,
+    pages: usize,
+    prev_url: Option<String>,
+    next_url: Option<String>,
+    /// The user signed in through the login form, who can log out.
+    session_user: Option<String>,
+}
+
+/// Version of the JSON listing schema, bumped on incompatible changes.
+const LIST_JSON_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    size: Option<u64>,
+    mtime: Option<String>,
+    mime: Option<String>,
+    url: &'a str,
+    epub_reader_url: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonListing<'a> {
+    version: u32,
+    path: &'a str,
+    total: usize,
+    page: usize,
+    pages: usize,
+    entries: Vec<JsonEntry<'a>>,
+}
+
+fn dir_json(
+    path: &str,
+    entries: &[DirEntryInfo],
+    total: usize,
+    page: usize,
+    pages: usize,
+) -> HttpResponse {
+    let entries = entries
+        .iter()
+        .map(|entry| JsonEntry {
+            name: &entry.name,
+            type_: if entry.is_dir { "dir" } else { "file" },
+            size: entry.size,
+            mtime: entry.modified.map(|mtime| {
+                DateTime::<Utc>::from(mtime).to_rfc3339_opts(SecondsFormat::Secs, true)
+            }),
+            mime: (!entry.is_dir)
+                .then(|| mime_guess::from_path(&entry.name).first())
+                .flatten()
+                .map(|mime| mime.to_string()),
+            url: &entry.url,
+            epub_reader_url: entry.epub_url.as_deref(),
+        })
+        .collect();
+    let listing = JsonListing {
+        version: LIST_JSON_VERSION,
+        path,
+        total,
+        page,
+        pages,
+        entries,
+    };
+    HttpResponse::Ok()
+        .insert_header((VARY, "Accept"))
+        .json(listing)
+}
+
+fn sort_entries(entries: &mut [DirEntryInfo], query: &ListQuery) {
+    entries.sort_by(|a, b| {
+        let ord = match query.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Mtime => a.modified.cmp(&b.modified),
+            SortKey::Type => natural_cmp(&a.ext(), &b.ext()),
+        }
+        .then_with(|| natural_cmp(&a.name, &b.name));
+        let ord = match query.order {
+            SortOrder::Asc => ord,
+            SortOrder::Desc => ord.reverse(),
+        };
+        if query.dirs_first {
+            b.is_dir.cmp(&a.is_dir).then(ord)
+        } else {
+            ord
+        }
+    });
+}
+
+fn format_mtime(mtime: SystemTime) -> String {
+    DateTime::<Utc>::from(mtime)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+fn breadcrumbs(req_path: &str, dir_query: &str) -> Vec<Crumb> {
+    let mut crumbs = vec![Crumb {
+        name: String::from("Home"),
+        url: format!("/{}", dir_query),
+    }];
+    let mut url = String::new();
+    for seg in req_path.split('/').filter(|seg| !seg.is_empty()) {
+        url.push('/');
+        url.push_str(seg);
+        crumbs.push(Crumb {
+            name: urlencoding::decode(seg)
+                .map(|name| name.into_owned())
+                .unwrap_or_else(|_| seg.to_string()),
+            url: format!("{}{}", url, dir_query),
+        });
+    }
+    crumbs
+}
+
+fn parent_url(req_path: &str, dir_query: &str) -> Option<String> {
+    let trimmed = req_path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return None;
+    }
+    let parent = &trimmed[..trimmed.rfind('/')? + 1];
+    Some(format!("{}{}", parent, dir_query))
+}
+
+async fn dir_get(req: &HttpRequest, path: &PathBuf, app_state: &AppState) -> HttpResponse {
+    let query = match ListQuery::from_request(req) {
+        Ok(query) => query,
+        Err(resp) => return resp,
+    };
+    let filter = match query.filter_pattern() {
+        Ok(filter) => filter,
+        Err(resp) => return resp,
+    };
+    let match_opts = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+
     let dir = fs::read_dir(&path).await;
     if dir.is_err() {
         return HttpResponse::InternalServerError().body(format!(
@@ -28,51 +242,313 @@ async fn dir_get(req: &HttpRequest, path: &PathBuf) -> HttpResponse {
         ));
     }
     let mut dir = dir.unwrap();
-    let mut vec = Vec::new();
+    let json = query.wants_json(req);
+    // links in JSON listings are plain resource URLs
+    let dir_query = if json {
+        String::new()
+    } else {
+        query.to_query_string(false)
+    };
+    let decoded_path = urlencoding::decode(req.path())
+        .map(|path| path.into_owned())
+        .unwrap_or_else(|_| String::from(req.path()));
+    let mut entries = Vec::new();
     while let Ok(Some(entry)) = dir.next_entry().await {
-        vec.push(entry);
-    }
-    vec.sort_unstable_by_key(|a| a.file_name());
-
-    for entry in vec {
-        let Ok(file_type) = entry.file_type().await else {
-            continue;
-        };
         let name = entry.file_name();
         let Some(name) = name.to_str() else {
             continue;
         };
-        out.push_str("<div>");
-        if file_type.is_dir() {
-            out.push_str("[+&nbsp;");
-        } else {
-            out.push_str("[-&nbsp;");
+        if let Some(filter) = &filter
+            && !filter.matches_with(name, match_opts)
+        {
+            continue;
         }
+        // entries the requester may not open, by themselves or through the symlink policy and
+        // the rules of the path a link leads to, are not disclosed
+        let entry_path = format!("{}/{}", decoded_path, name);
+        let Ok(resolved) =
+            path_res::resolve(&app_state.root_dir, &entry_path, app_state.symlink_policy).await
+        else {
+            continue;
+        };
+        if acl::authorize_resolved(
+            req,
+            &app_state.acl,
+            &app_state.root_dir,
+            &resolved,
+            &entry_path,
+            &[Perm::Read, Perm::List],
+        )
+        .await
+        .is_err()
+        {
+            continue;
+        }
+        // follow symlinks so that linked directories are listed as directories
+        let Ok(meta) = fs::metadata(&resolved).await else {
+            continue;
+        };
         let mut url = String::from(req.path());
         if !url.ends_with("/") {
             url.push('/');
         }
         url.push_str(&urlencoding::encode(name));
-        let anchor = format!(r#"<a href="{}">{}</a>]"#, &url, &name);
-        out.push_str(&anchor);
-        if file_type.is_file()
-            && let Ok(meta) = entry.metadata().await
-        {
-            out.push_str(&format!("&nbsp;[{}]", format_size(meta.len()),));
-        }
-        if name.ends_with(".epub") {
-            out.push_str(&format!(r#"&nbsp;[<a href="/epub_toc{}">Read</a>]"#, &url));
+        let size = meta.is_file().then_some(meta.len());
+        let epub_url =
+            (meta.is_file() && name.ends_with(".epub")).then(|| format!("/epub_toc{}", &url));
+        if meta.is_dir() {
+            url.push_str(&dir_query);
         }
-        out.push_str("</div>");
+        let modified = meta.modified().ok();
+        entries.push(DirEntryInfo {
+            name: String::from(name),
+            url,
+            is_dir: meta.is_dir(),
+            size,
+            size_str: size.map(format_size),
+            mtime: modified.map(format_mtime),
+            epub_url,
+            modified,
+        });
     }
+    sort_entries(&mut entries, &query);
 
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(out)
+    let total = entries.len();
+    let (mut page, mut pages) = (1, 1);
+    if query.per_page > 0 {
+        pages = total.div_ceil(query.per_page).max(1);
+        page = query.page.min(pages);
+        entries = entries
+            .into_iter()
+            .skip((page - 1) * query.per_page)
+            .take(query.per_page)
+            .collect();
+    }
+    if json {
+        return dir_json(&decoded_path, &entries, total, page, pages);
+    }
+
+    let query = query.with_page(page);
+    let page_url = |page| {
+        format!(
+            "{}{}",
+            req.path(),
+            query.with_page(page).to_query_string(true)
+        )
+    };
+    let sort_url = |key| {
+        format!(
+            "{}{}",
+            req.path(),
+            query.sorted_by(key).to_query_string(true)
+        )
+    };
+
+    let ctx = DirListCtx {
+        path: &decoded_path,
+        breadcrumbs: breadcrumbs(req.path(), &dir_query),
+        parent_url: parent_url(req.path(), &dir_query),
+        entries,
+        sort: query.sort,
+        order: query.order,
+        dirs_first: query.dirs_first,
+        filter: query.filter.as_deref(),
+        sort_urls: SortUrls {
+            name: sort_url(SortKey::Name),
+            size: sort_url(SortKey::Size),
+            mtime: sort_url(SortKey::Mtime),
+            type_: sort_url(SortKey::Type),
+        },
+        dirs_first_url: format!(
+            "{}{}",
+            req.path(),
+            query
+                .with_dirs_first(!query.dirs_first)
+                .to_query_string(true)
+        ),
+        view_params: query.params(false),
+        total,
+        page,
+        pages,
+        prev_url: (page > 1).then(|| page_url(page - 1)),
+        next_url: (page < pages).then(|| page_url(page + 1)),
+        session_user: req
+            .extensions()
+            .get::<Identity>()
+            .filter(|identity| identity.session)
+            .map(|identity| identity.user.clone()),
+    };
+    match app_state.templates.render("dir_list.html", ctx) {
+        Ok(out) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .insert_header((VARY, "Accept"))
+            .body(out),
+        Err(err) => HttpResponse::InternalServerError()
+            .body(format!("Rendering dir listing failed: {:?}", err)),
+    }
+}
+
+fn file_etag(meta: &Metadata) -> EntityTag {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    EntityTag::new_strong(format!(
+        "{:x}-{:x}-{:x}",
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        meta.len()
+    ))
+}
+
+/// Returns `true` when the request preconditions say the client copy is still fresh, which means
+/// a `304 Not Modified` should be sent. `If-None-Match` takes precedence over `If-Modified-Since`.
+fn not_modified(req: &HttpRequest, etag: &EntityTag, mtime: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(items) => items.iter().any(|item| item.weak_eq(etag)),
+        };
+    }
+    if let (Some(since), Some(mtime)) = (req.get_header::<IfModifiedSince>(), mtime) {
+        // HTTP dates only have one second resolution
+        let mtime = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let since = SystemTime::from(since.0)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        return mtime <= since;
+    }
+    false
+}
+
+/// Returns `true` when the `Range` header should be honored according to `If-Range`.
+fn if_range_matches(req: &HttpRequest, etag: &EntityTag, last_modified: Option<HttpDate>) -> bool {
+    match req.get_header::<IfRange>() {
+        None => true,
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(IfRange::Date(date)) => last_modified.is_some_and(|lm| lm == date),
+    }
+}
+
+async fn open_range(
+    path: &PathBuf,
+    start: u64,
+    len: u64,
+) -> std::io::Result<ReaderStream<Take<fs::File>>> {
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(ReaderStream::new(file.take(len)))
 }
 
-async fn file_get(size: u64, path: &PathBuf) -> HttpResponse {
-    let file = fs::File::open(path).await;
+async fn file_get(
+    req: &HttpRequest,
+    meta: &Metadata,
+    path: &PathBuf,
+    reservation: Option<share::Reservation>,
+) -> HttpResponse {
+    let size = meta.len();
+    let mtime = meta.modified().ok();
+    let etag = file_etag(meta);
+    let last_modified = mtime.map(HttpDate::from);
+    let mime = mime_guess::from_path(path).first();
+
+    if not_modified(req, &etag, mtime) {
+        let mut resp_builder = HttpResponse::NotModified();
+        resp_builder.insert_header(ETag(etag));
+        if let Some(last_modified) = last_modified {
+            resp_builder.insert_header(LastModified(last_modified));
+        }
+        return resp_builder.finish();
+    }
+
+    let mut ranges = Vec::new();
+    if let Some(Range::Bytes(specs)) = req.get_header::<Range>()
+        && if_range_matches(req, &etag, last_modified)
+    {
+        ranges = specs
+            .iter()
+            .filter_map(|spec| spec.to_satisfiable_range(size))
+            .collect::<Vec<_>>();
+        if ranges.is_empty() || ranges.len() > MAX_RANGES {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(size),
+                }))
+                .finish();
+        }
+    }
+
+    let mut resp_builder = if ranges.is_empty() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::PartialContent()
+    };
+    resp_builder.insert_header((ACCEPT_RANGES, "bytes"));
+    resp_builder.insert_header(ETag(etag.clone()));
+    if let Some(last_modified) = last_modified {
+        resp_builder.insert_header(LastModified(last_modified));
+    }
+    if !ranges.is_empty() {
+        // compressing partial content would make the byte offsets meaningless
+        resp_builder.insert_header(ContentEncoding::Identity);
+    }
+
+    if ranges.len() > 1 {
+        let boundary = format!(
+            "{:x}{:x}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            size
+        );
+        let mut parts: Vec<BodyStream> = Vec::new();
+        let mut total = 0u64;
+        for (start, end) in ranges {
+            let mut head = format!("\r\n--{}\r\n", boundary);
+            if let Some(mime) = &mime {
+                head.push_str(&format!("Content-Type: {}\r\n", mime));
+            }
+            head.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                start, end, size
+            ));
+            let part = match open_range(path, start, end - start + 1).await {
+                Ok(part) => part,
+                Err(err) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Opening file [{:?}] failed: {:?}", &path, err));
+                }
+            };
+            total += head.len() as u64 + end - start + 1;
+            parts.push(Box::pin(stream::once(future::ready(Ok(Bytes::from(head))))));
+            parts.push(Box::pin(part));
+        }
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        total += tail.len() as u64;
+        parts.push(Box::pin(stream::once(future::ready(Ok(Bytes::from(tail))))));
+        resp_builder.insert_header(("Content-Length", total.to_string()));
+        resp_builder.content_type(format!("multipart/byteranges; boundary={}", boundary));
+        return resp_builder.streaming(share::track(stream::iter(parts).flatten(), reservation));
+    }
+
+    let (start, len) = match ranges.first() {
+        Some(&(start, end)) => {
+            resp_builder.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(size),
+            }));
+            (start, end - start + 1)
+        }
+        None => (0, size),
+    };
+    let file = open_range(path, start, len).await;
     if file.is_err() {
         return HttpResponse::InternalServerError().body(format!(
             "Opening file [{:?}] failed: {:?}",
@@ -80,41 +556,107 @@ async fn file_get(size: u64, path: &PathBuf) -> HttpResponse {
             file.err().unwrap()
         ));
     }
-    let mut resp_builder = HttpResponse::Ok();
-    resp_builder.insert_header(("Content-Length", size.to_string()));
-    if let Some(mime) = mime_guess::from_path(path).first() {
+    resp_builder.insert_header(("Content-Length", len.to_string()));
+    if let Some(mime) = mime {
         resp_builder.content_type(mime);
     }
-    resp_builder.streaming(ReaderStream::new(file.unwrap()))
+    resp_builder.streaming(share::track(file.unwrap(), reservation))
 }
 
 pub async fn fs_get(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
-    let mut path = app_state.root_dir.clone();
     let Ok(decoded_path) = urlencoding::decode(req.path()) else {
         return HttpResponse::BadRequest().body("Invalid request path");
     };
-    if req.path() != "/" {
-        path.push(&(&*decoded_path)[1..]);
+    // checked before touching the file system, so that the existence of files is not revealed
+    if let Err(resp) = acl::authorize(
+        &req,
+        &app_state.acl,
+        &decoded_path,
+        &[Perm::Read, Perm::List],
+    ) {
+        return resp;
     }
+    let path = match path_res::resolve(&app_state.root_dir, &decoded_path, app_state.symlink_policy)
+        .await
+    {
+        Ok(path) => path,
+        Err(err) => return err.to_response(),
+    };
     let Ok(meta) = fs::metadata(&path).await else {
         return HttpResponse::NotFound().body("Resource not found");
     };
 
+    let perm = if meta.is_dir() {
+        Perm::List
+    } else {
+        Perm::Read
+    };
+    if let Err(resp) = acl::authorize_resolved(
+        &req,
+        &app_state.acl,
+        &app_state.root_dir,
+        &path,
+        &decoded_path,
+        &[perm],
+    )
+    .await
+    {
+        return resp;
+    }
+
     if meta.is_dir() {
-        return dir_get(&req, &path).await;
+        return dir_get(&req, &path, &app_state).await;
     }
 
     if meta.is_file() {
-        return file_get(meta.len(), &path).await;
+        let etag = file_etag(&meta);
+        let download = (req.method() == Method::GET).then(|| share::FileDownload {
+            etag: etag.tag(),
+            size: meta.len(),
+            requested: requested_len(&req, &meta),
+        });
+        let reservation = match share::limit_download(&req, &app_state, &decoded_path, download) {
+            Ok(reservation) => reservation,
+            Err(resp) => return resp,
+        };
+        return file_get(&req, &meta, &path, reservation).await;
     }
 
     HttpResponse::NotFound().body("Resource not found")
 }
 
+/// The number of bytes of the file a GET asks for, the length of its ranges when they are
+/// served, else the whole file.
+fn requested_len(req: &HttpRequest, meta: &Metadata) -> u64 {
+    let size = meta.len();
+    let Some(Range::Bytes(specs)) = req.get_header::<Range>() else {
+        return size;
+    };
+    if !if_range_matches(
+        req,
+        &file_etag(meta),
+        meta.modified().ok().map(HttpDate::from),
+    ) {
+        return size;
+    }
+    let ranges = specs
+        .iter()
+        .filter_map(|spec| spec.to_satisfiable_range(size))
+        .collect::<Vec<_>>();
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        return size;
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| end - start + 1)
+        .fold(0, u64::saturating_add)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::AppState;
+    use crate::test_util::TempDir;
     use actix_http::StatusCode;
     use actix_web::dev::ServiceResponse;
     use actix_web::test;
@@ -127,8 +669,10 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body = test::read_body(ServiceResponse::new(req, resp)).await;
         let body = String::from_utf8_lossy(&body);
-        assert!(body.contains(r#"[-&nbsp;<a href="/Cargo.toml">Cargo.toml</a>]"#));
-        assert!(body.contains(r#"[+&nbsp;<a href="/src">src</a>]"#));
+        assert!(body.contains(r#"<a href="/Cargo.toml">Cargo.toml</a>"#));
+        assert!(body.contains(r#"<tr class="dir"><td class="name"><a href="/src">src/</a>"#));
+        assert!(body.contains("<title>Index of /</title>"));
+        assert!(!body.contains(r#"">../</a>"#));
     }
 
     #[actix_web::test]
@@ -139,7 +683,9 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body = test::read_body(ServiceResponse::new(req, resp)).await;
         let body = String::from_utf8_lossy(&body);
-        assert!(body.contains(r#"[-&nbsp;<a href="/main.rs">main.rs</a>]"#));
+        assert!(
+            body.contains(r#"<tr class="file"><td class="name"><a href="/main.rs">main.rs</a>"#)
+        );
     }
 
     #[actix_web::test]
@@ -150,7 +696,9 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body = test::read_body(ServiceResponse::new(req, resp)).await;
         let body = String::from_utf8_lossy(&body);
-        assert!(body.contains(r#"[-&nbsp;<a href="/src/main.rs">main.rs</a>]"#));
+        assert!(body.contains(r#"<a href="/src/main.rs">main.rs</a>"#));
+        assert!(body.contains(r#"<a href="/">Home</a> / <a href="/src">src</a></h1>"#));
+        assert!(body.contains(r#"<a href="/">../</a>"#));
     }
 
     #[actix_web::test]
@@ -163,7 +711,149 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body = test::read_body(ServiceResponse::new(req, resp)).await;
         let body = String::from_utf8_lossy(&body);
-        assert!(body.contains(r#"[65.92 KB]&nbsp;[<a href="/epub_toc/res_dir/v2.epub">Read</a>]"#));
+        assert!(body.contains(
+            r#"<a href="/res_dir/v2.epub">v2.epub</a></td><td class="size">65.92 KB</td>"#
+        ));
+        assert!(body.contains(r#"<a href="/epub_toc/res_dir/v2.epub">Read</a>"#));
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_dir_sorted() {
+        let root = TempDir::new("sort");
+        std::fs::create_dir_all(root.join("dir10")).unwrap();
+        std::fs::create_dir_all(root.join("dir9")).unwrap();
+        std::fs::write(root.join("scan10.pdf"), "1").unwrap();
+        std::fs::write(root.join("Scan2.pdf"), "333").unwrap();
+        std::fs::write(root.join("scan1.txt"), "22").unwrap();
+        let app_data = web::Data::new(AppState::new(root.to_path_buf()));
+        let names = |body: &str| {
+            body.split(r#"<td class="name"><a href=""#)
+                .skip(1)
+                .map(|part| {
+                    part.split('>')
+                        .nth(1)
+                        .unwrap()
+                        .split('<')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        let get = |uri: &'static str| {
+            let app_data = app_data.clone();
+            async move {
+                let req = test::TestRequest::default().uri(uri).to_http_request();
+                let resp = fs_get(req.clone(), app_data).await;
+                let status = resp.status();
+                let body = test::read_body(ServiceResponse::new(req, resp)).await;
+                (status, String::from_utf8_lossy(&body).to_string())
+            }
+        };
+
+        let (status, body) = get("/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            names(&body),
+            vec!["dir9/", "dir10/", "scan1.txt", "Scan2.pdf", "scan10.pdf"]
+        );
+        assert!(body.contains(r#"<a href="/?order=desc">Name</a> &#9650;"#));
+        assert!(body.contains(r#"<a href="/?sort=size">Size</a></th>"#));
+
+        let (_, body) = get("/?sort=size&order=desc&dirs_first=true").await;
+        assert_eq!(
+            names(&body),
+            vec!["dir10/", "dir9/", "Scan2.pdf", "scan1.txt", "scan10.pdf"]
+        );
+        // directory links keep the view settings
+        assert!(body.contains(
+            r#"<a href="/dir10?sort=size&amp;order=desc&amp;dirs_first=true">dir10/</a>"#
+        ));
+        assert!(body.contains(r#"<a href="/?sort=size&amp;dirs_first=true">Size</a> &#9660;"#));
+
+        let (_, body) = get("/?sort=type&filter=SCAN*").await;
+        assert_eq!(names(&body), vec!["Scan2.pdf", "scan10.pdf", "scan1.txt"]);
+        assert!(body.contains(r#"value="SCAN*""#));
+
+        let (_, body) = get("/?per_page=2&page=2").await;
+        assert_eq!(names(&body), vec!["scan1.txt", "Scan2.pdf"]);
+        assert!(body.contains("Page 2 of 3"));
+        assert!(body.contains(r#"<a href="/?per_page=2">Prev</a>"#));
+        assert!(body.contains(r#"<a href="/?per_page=2&amp;page=3">Next</a>"#));
+        assert!(body.contains(r#"<input type="hidden" name="per_page" value="2">"#));
+
+        let (status, _) = get("/?sort=color").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get("/?filter=%5B").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_dir_json() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        for req in [
+            test::TestRequest::default()
+                .uri("/res_dir?format=json&sort=size")
+                .to_http_request(),
+            test::TestRequest::default()
+                .uri("/res_dir?sort=size")
+                .insert_header(("Accept", "application/json"))
+                .to_http_request(),
+        ] {
+            let resp = fs_get(req.clone(), app_data.clone()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers().get("Content-Type").unwrap(),
+                "application/json"
+            );
+            assert_eq!(resp.headers().get("Vary").unwrap(), "Accept");
+            let body = test::read_body(ServiceResponse::new(req, resp)).await;
+            let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(listing["version"], 1);
+            assert_eq!(listing["path"], "/res_dir");
+            assert_eq!(listing["page"], 1);
+            let entries = listing["entries"].as_array().unwrap();
+            assert_eq!(entries.len(), listing["total"].as_u64().unwrap() as usize);
+            assert_eq!(entries[0]["name"], "images");
+            assert_eq!(entries[0]["type"], "dir");
+            assert_eq!(entries[0]["url"], "/res_dir/images");
+            assert!(entries[0]["size"].is_null());
+            assert!(entries[0]["mime"].is_null());
+            let epub = entries.iter().find(|e| e["name"] == "v2.epub").unwrap();
+            assert_eq!(epub["type"], "file");
+            assert_eq!(epub["size"], 67506);
+            assert_eq!(epub["mime"], "application/epub+zip");
+            assert_eq!(epub["url"], "/res_dir/v2.epub");
+            assert_eq!(epub["epub_reader_url"], "/epub_toc/res_dir/v2.epub");
+            assert!(epub["mtime"].as_str().unwrap().ends_with('Z'));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_dir_escaped() {
+        let root = TempDir::new("escape");
+        std::fs::create_dir_all(root.join("<sub> & 'dir'")).unwrap();
+        std::fs::write(root.join(r#"<b onclick="x">.epub"#), "").unwrap();
+        let req = test::TestRequest::default()
+            .uri("/%3Csub%3E%20%26%20%27dir%27")
+            .to_http_request();
+        let app_data = web::Data::new(AppState::new(root.to_path_buf()));
+        let resp = fs_get(req.clone(), app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(ServiceResponse::new(req, resp)).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("<title>Index of /&lt;sub&gt; &amp; &#39;dir&#39;</title>"));
+        assert!(body.contains(
+            r#"<a href="/%3Csub%3E%20%26%20%27dir%27">&lt;sub&gt; &amp; &#39;dir&#39;</a></h1>"#
+        ));
+
+        let req = test::TestRequest::default().to_http_request();
+        let resp = fs_get(req.clone(), app_data).await;
+        let body = test::read_body(ServiceResponse::new(req, resp)).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains("<b "));
+        assert!(body.contains(r#"&lt;b onclick=&quot;x&quot;&gt;.epub</a>"#));
+        assert!(body.contains(r#"<a href="/epub_toc/%3Cb%20onclick%3D%22x%22%3E.epub">Read</a>"#));
     }
 
     #[actix_web::test]
@@ -184,6 +874,137 @@ mod tests {
         assert!(body.to_vec() == file_cont);
     }
 
+    #[actix_web::test]
+    async fn test_fs_get_single_range() {
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("Range", "bytes=10-19"))
+            .to_http_request();
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let resp = fs_get(req.clone(), app_data).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let file_cont = fs::read("res_dir/dummy.pdf").await.unwrap();
+        assert_eq!(
+            resp.headers().get("Content-Range").unwrap(),
+            &format!("bytes 10-19/{}", file_cont.len())
+        );
+        assert_eq!(resp.headers().get("Accept-Ranges").unwrap(), "bytes");
+        let body = test::read_body(ServiceResponse::new(req, resp)).await;
+        assert_eq!(body.to_vec(), file_cont[10..20].to_vec());
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_multi_range() {
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("Range", "bytes=0-4,-5"))
+            .to_http_request();
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let resp = fs_get(req.clone(), app_data).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = resp
+            .headers()
+            .get("Content-Type")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let boundary = content_type.split("boundary=").nth(1).unwrap().to_string();
+        let content_length: usize = resp
+            .headers()
+            .get("Content-Length")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = test::read_body(ServiceResponse::new(req, resp)).await;
+        assert_eq!(body.len(), content_length);
+        let file_cont = fs::read("res_dir/dummy.pdf").await.unwrap();
+        let len = file_cont.len();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{}\r\nContent-Type: application/pdf\r\nContent-Range: bytes 0-4/{}\r\n\r\n",
+                boundary, len
+            )
+            .as_bytes(),
+        );
+        expected.extend_from_slice(&file_cont[0..5]);
+        expected.extend_from_slice(
+            format!("\r\n--{}\r\nContent-Type: application/pdf\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, len - 5, len - 1, len).as_bytes(),
+        );
+        expected.extend_from_slice(&file_cont[len - 5..]);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert!(body.to_vec() == expected);
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_range_not_satisfiable() {
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("Range", "bytes=100000000-"))
+            .to_http_request();
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let resp = fs_get(req.clone(), app_data).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        let len = fs::metadata("res_dir/dummy.pdf").await.unwrap().len();
+        assert_eq!(
+            resp.headers().get("Content-Range").unwrap(),
+            &format!("bytes */{}", len)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_conditional() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .to_http_request();
+        let resp = fs_get(req, app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers().get("ETag").unwrap().clone();
+        let last_modified = resp.headers().get("Last-Modified").unwrap().clone();
+        // matching etag
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("If-None-Match", etag.clone()))
+            .to_http_request();
+        let resp = fs_get(req, app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        // not modified since
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("If-Modified-Since", last_modified.clone()))
+            .to_http_request();
+        let resp = fs_get(req, app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        // If-None-Match takes precedence
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("If-None-Match", r#""other""#))
+            .insert_header(("If-Modified-Since", last_modified))
+            .to_http_request();
+        let resp = fs_get(req, app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // If-Range with current etag honors the range
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("Range", "bytes=0-9"))
+            .insert_header(("If-Range", etag))
+            .to_http_request();
+        let resp = fs_get(req, app_data.clone()).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        // If-Range with stale etag falls back to the full file
+        let req = test::TestRequest::default()
+            .uri("/res_dir/dummy.pdf")
+            .insert_header(("Range", "bytes=0-9"))
+            .insert_header(("If-Range", r#""stale""#))
+            .to_http_request();
+        let resp = fs_get(req, app_data).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
     #[actix_web::test]
     async fn test_fs_get_non_exist() {
         let req = test::TestRequest::default()
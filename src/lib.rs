@@ -1,22 +1,43 @@
-use actix_web::middleware::{Compress, Condition};
-use actix_web::{App, HttpServer, dev::ServiceRequest, web};
-use actix_web_httpauth::{
-    extractors::{AuthenticationError, basic::BasicAuth},
-    middleware::HttpAuthentication,
-};
+use actix_http::HttpService;
+use actix_service::{IntoServiceFactory, map_config};
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderValue;
+use actix_web::middleware::{Compress, from_fn};
+use actix_web::{App, HttpServer, web};
 use anyhow::Result;
-use lru::LruCache;
-use rustls::ServerConfig;
-use std::cell::RefCell;
-use std::io::BufReader;
-use std::num::NonZeroUsize;
+use futures_util::future;
+use std::any::Any;
+use std::net::{TcpListener, ToSocketAddrs};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
 
+// Unix sockets, systemd socket activation, signals and file ownership are used throughout.
+#[cfg(not(unix))]
+compile_error!("my-cont-srv only supports Unix-like systems");
+
+mod acl;
+pub mod auth;
+mod cache;
+pub mod check;
 pub mod config;
 mod epub_proc;
 mod fs_proc;
+mod list_query;
+mod listen;
+mod path_res;
+mod reload;
+mod session;
+pub mod share;
+mod systemd;
+mod templates;
+#[cfg(test)]
+mod test_util;
+mod throttle;
+pub mod tls;
+mod vhost;
 
 fn app_config(cfg: &mut web::ServiceConfig) {
     cfg.route(
@@ -27,128 +48,418 @@ fn app_config(cfg: &mut web::ServiceConfig) {
         "/epub_cont/{filepath}/{innerpath:.*}",
         web::get().to(epub_proc::epub_cont),
     );
+    cfg.route("/cache_stats", web::get().to(cache::cache_stats));
+    cfg.route("/login", web::get().to(session::login_get));
+    cfg.route("/login", web::post().to(session::login_post));
+    cfg.route("/logout", web::post().to(session::logout));
+    cfg.route("/share", web::post().to(share::create_share));
     cfg.default_service(web::get().to(fs_proc::fs_get));
 }
 
 struct AppState {
     root_dir: PathBuf,
-    epub_toc_cache: Mutex<LruCache<String, String>>,
-    epub_cont_cache: Mutex<LruCache<String, (String, Vec<u8>)>>,
+    symlink_policy: config::SymlinkPolicy,
+    acl: acl::Acl,
+    shares: Arc<share::ShareStore>,
+    /// Counts wrong share passwords, whether or not users are configured.
+    share_throttle: throttle::Throttle,
+    templates: templates::Templates,
+    epub_toc_cache: Mutex<cache::BoundedCache<String, cache::Cached<String>>>,
+    epub_cont_cache: Mutex<cache::BoundedCache<String, cache::Cached<epub_proc::EpubCont>>>,
+    epub_doc_cache: Mutex<cache::BoundedCache<epub_proc::DocKey, epub_proc::SharedDoc>>,
+    epub_permits: Arc<Semaphore>,
+    epub_timeout: Duration,
+    /// Whether `/cache_stats` serves the cache counters rather than a file.
+    cache_stats: bool,
+    file_watcher: std::sync::Mutex<Option<notify::RecommendedWatcher>>,
 }
 
 impl AppState {
     fn new(root_dir: PathBuf) -> Self {
         AppState {
             root_dir,
-            epub_toc_cache: Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap())),
-            epub_cont_cache: Mutex::new(LruCache::new(NonZeroUsize::new(200).unwrap())),
+            symlink_policy: config::SymlinkPolicy::default(),
+            acl: acl::Acl::default(),
+            shares: Arc::new(share::ShareStore::new(None, &[])),
+            share_throttle: throttle::Throttle::new(throttle::AuthLimits::default()),
+            templates: templates::Templates::default(),
+            epub_toc_cache: Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+                max_entries: 0,
+                max_bytes: config::DEFAULT_EPUB_TOC_CACHE_BYTES,
+                max_entry_bytes: config::DEFAULT_CACHE_MAX_ENTRY_BYTES,
+            })),
+            epub_cont_cache: Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+                max_entries: 0,
+                max_bytes: config::DEFAULT_EPUB_CONT_CACHE_BYTES,
+                max_entry_bytes: config::DEFAULT_CACHE_MAX_ENTRY_BYTES,
+            })),
+            epub_doc_cache: Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+                max_entries: config::DEFAULT_EPUB_DOC_CACHE_ENTRIES,
+                ..Default::default()
+            })),
+            epub_permits: Arc::new(Semaphore::new(config::DEFAULT_EPUB_CONCURRENCY)),
+            epub_timeout: Duration::from_secs(config::DEFAULT_EPUB_TIMEOUT),
+            cache_stats: false,
+            file_watcher: std::sync::Mutex::new(None),
         }
     }
 }
 
-fn tls_config(cert_path: &PathBuf, key_path: &PathBuf) -> Result<ServerConfig> {
-    let builder = ServerConfig::builder().with_no_client_auth();
+/// Builds the state and the authentication of a site from its config, `hosts` being the host
+/// names of a vhost. On reload `previous` is the site being replaced with the config it was built
+/// from, whose login sessions, authentication failure counts and share download counts are kept.
+fn build_site(
+    config: &config::Config,
+    hosts: &[String],
+    previous: Option<(&config::Config, &vhost::Site)>,
+) -> Result<vhost::Site> {
+    let users = auth::load_users(config)?;
+    // client certificates sign users in, so access control applies even without passwords
+    let enable_auth = !users.is_empty() || config.client_ca_path.is_some();
+    let limits = throttle::AuthLimits {
+        max_failures: config.auth_max_failures,
+        lockout: Duration::from_secs(config.auth_lockout),
+        max_lockout: Duration::from_secs(config.auth_max_lockout),
+        verify_concurrency: config.auth_verify_concurrency,
+    };
+    let mut auth_info = auth::AuthInfo::new(users).with_limits(limits);
+    let previous_auth = previous.and_then(|(_, site)| site.auth_info.as_ref());
+    if let Some(previous_auth) = previous_auth {
+        auth_info = auth_info.with_throttle_of(previous_auth.throttle());
+    }
+    if config.client_ca_path.is_some() && config.client_auth == config::ClientAuth::CertOrPassword {
+        auth_info = auth_info.with_credentials_required();
+    }
+    if config.form_login {
+        let sessions = previous_auth
+            .and_then(auth::AuthInfo::session_store)
+            .unwrap_or_else(|| {
+                Arc::new(session::SessionStore::new(
+                    Duration::from_secs(config.session_idle_timeout),
+                    Duration::from_secs(config.session_remember_days.saturating_mul(24 * 3600)),
+                ))
+            });
+        auth_info = auth_info.with_sessions(sessions);
+    }
 
-    let cert_file = &mut BufReader::new(std::fs::File::open(cert_path)?);
-    let key_file = &mut BufReader::new(std::fs::File::open(key_path)?);
+    let mut app_state = AppState::new(config.root_dir.clone());
+    app_state.symlink_policy = config.symlink_policy;
+    app_state.acl = acl::Acl::new(config.acl.clone(), config.groups.clone())?;
+    app_state.shares = match previous {
+        Some((old, site))
+            if old.share_secret == config.share_secret && site.app_state.shares.is_for(hosts) =>
+        {
+            site.app_state.shares.clone()
+        }
+        _ => Arc::new(share::ShareStore::new(
+            config.share_secret.as_deref(),
+            hosts,
+        )),
+    };
+    app_state.share_throttle = match previous {
+        Some((_, site)) => site.app_state.share_throttle.with_limits(limits),
+        None => throttle::Throttle::new(limits),
+    };
+    app_state.templates = templates::Templates::new(config.templates_dir.as_deref())?;
+    app_state.epub_permits = Arc::new(Semaphore::new(config.epub_concurrency));
+    app_state.epub_timeout = Duration::from_secs(config.epub_timeout);
+    app_state.cache_stats = config.cache_stats;
+    app_state.epub_toc_cache = Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+        max_entries: 0,
+        max_bytes: config.epub_toc_cache_bytes,
+        max_entry_bytes: config.cache_max_entry_bytes,
+    }));
+    app_state.epub_cont_cache = Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+        max_entries: 0,
+        max_bytes: config.epub_cont_cache_bytes,
+        max_entry_bytes: config.cache_max_entry_bytes,
+    }));
+    app_state.epub_doc_cache = Mutex::new(cache::BoundedCache::new(cache::CacheLimits {
+        max_entries: config.epub_doc_cache_entries,
+        ..Default::default()
+    }));
+    let app_data = web::Data::new(app_state);
+    if config.watch_files {
+        let watcher = cache::watch_root(&app_data.clone().into_inner())?;
+        *app_data.file_watcher.lock().unwrap() = Some(watcher);
+    }
+    Ok(vhost::Site {
+        app_state: app_data,
+        auth_info: enable_auth.then_some(auth_info),
+    })
+}
 
-    let cert_chain = rustls_pemfile::certs(cert_file)
-        .map(|item| item.unwrap())
-        .collect();
-    let key = rustls_pemfile::private_key(key_file)?;
+/// Builds the top level site and the vhost sites, see [`build_site`].
+fn build_sites(
+    config: &config::Config,
+    previous: Option<(&config::Config, &vhost::Vhosts)>,
+) -> Result<vhost::Vhosts> {
+    let default = previous.map(|(old, vhosts)| (old, vhosts.default_site()));
+    let mut vhosts = vhost::Vhosts::new(build_site(config, &[], default)?);
+    for entry in &config.vhosts {
+        let previous = previous.and_then(|(old, vhosts)| {
+            let site = vhosts.get(entry.hosts.first()?)?;
+            Some((old, site))
+        });
+        vhosts.add(
+            &entry.hosts,
+            build_site(&vhost::vhost_config(config, entry), &entry.hosts, previous)?,
+        );
+    }
+    Ok(vhosts)
+}
 
-    let config = builder.with_single_cert(cert_chain, key.unwrap())?;
-    Ok(config)
+/// Starts the server of the plain HTTP listeners, whose app only redirects to HTTPS on
+/// `https_port`. It stops with the main server.
+fn redirect_server(
+    https_port: u16,
+    listeners: Vec<listen::Listener>,
+    shutdown_timeout: u64,
+) -> Result<actix_server::ServerHandle> {
+    let https_port = web::Data::new(tls::HttpsPort(https_port));
+    let app = move || {
+        App::new()
+            .app_data(https_port.clone())
+            .default_service(web::to(tls::redirect_plain))
+    };
+    let mut server = HttpServer::new(app)
+        .workers(1)
+        .shutdown_timeout(shutdown_timeout)
+        .disable_signals();
+    for listener in listeners {
+        server = match listener {
+            listen::Listener::Redirect { address, port } => {
+                server.bind((address.as_str(), port))?
+            }
+            listen::Listener::InheritedRedirect { fd } => {
+                server.listen(listen::take_inherited(fd))?
+            }
+            _ => unreachable!("only redirect listeners are passed"),
+        };
+    }
+    let server = server.run();
+    let handle = server.handle();
+    tokio::spawn(server);
+    Ok(handle)
 }
 
-#[derive(Clone)]
-struct AuthInfo {
-    user: String,
-    hash: String,
-    cached_pass: Arc<Mutex<RefCell<String>>>,
+/// The app of the main server. `hsts` is the value of the `Strict-Transport-Security` header,
+/// added to responses over HTTPS outside the other middlewares so that their responses get it as
+/// well.
+fn main_app(
+    sites: web::Data<vhost::SiteTable>,
+    hsts: Option<String>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .configure(app_config)
+        .app_data(sites)
+        .wrap(Compress::default())
+        .wrap(from_fn(auth::authenticate))
+        .app_data(web::Data::new(tls::Hsts(
+            hsts.and_then(|hsts| HeaderValue::try_from(hsts).ok()),
+        )))
+        .wrap(from_fn(vhost::select))
+        .wrap(from_fn(tls::add_hsts))
 }
 
-impl AuthInfo {
-    fn new(user: &str, hash: &str) -> Self {
-        Self {
-            user: String::from(user),
-            hash: String::from(hash),
-            cached_pass: Arc::new(Mutex::new(RefCell::new(String::new()))),
+/// The server of the HTTPS listeners when `tls_alpn` offers a single protocol. The listeners of
+/// `HttpServer` always offer both `h2` and `http/1.1`, so the HTTP service is set up here the way
+/// they do it, for one protocol. The app is not told that it is served over HTTPS, requests are
+/// recognized by [`tls::is_https`] instead.
+fn alpn_server(
+    config: &config::Config,
+    sites: web::Data<vhost::SiteTable>,
+    hsts: Option<String>,
+    mut server_config: rustls::ServerConfig,
+    listeners: Vec<listen::Listener>,
+) -> Result<actix_server::ServerBuilder> {
+    let h2 = config.tls_alpn.contains(&config::AlpnProtocol::H2);
+    if !h2 {
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    }
+    let on_connect = Arc::new(tls::on_connect(config.client_cert_user));
+    let mut builder = actix_server::Server::build()
+        .workers(config.workers)
+        .shutdown_timeout(config.shutdown_timeout)
+        .disable_signals();
+    let mut tcp_listeners = Vec::new();
+    for listener in listeners {
+        match listener {
+            listen::Listener::Tcp { address, port, .. } => {
+                for addr in (address.as_str(), port).to_socket_addrs()? {
+                    tcp_listeners.push(TcpListener::bind(addr)?);
+                }
+            }
+            listen::Listener::InheritedTcp { fd, .. } => {
+                tcp_listeners.push(listen::take_inherited(fd));
+            }
+            _ => unreachable!("only HTTPS listeners are passed"),
         }
     }
+    for listener in tcp_listeners {
+        let name = format!("my-cont-srv-{}", listener.local_addr()?);
+        let (sites, hsts) = (sites.clone(), hsts.clone());
+        let (on_connect, server_config) = (on_connect.clone(), server_config.clone());
+        let service = move || {
+            let app = map_config(main_app(sites.clone(), hsts.clone()).into_factory(), |_| {
+                AppConfig::default()
+            });
+            let on_connect = on_connect.clone();
+            let builder = HttpService::build()
+                .on_connect_ext(move |io: &_, data: _| (on_connect)(io as &dyn Any, data));
+            (builder, app, server_config.clone())
+        };
+        builder = match h2 {
+            true => builder.listen(name, listener, move || {
+                let (builder, app, server_config) = service();
+                builder.h2(app).rustls_0_23(server_config)
+            })?,
+            false => builder.listen(name, listener, move || {
+                let (builder, app, server_config) = service();
+                builder.h1(app).rustls_0_23(server_config)
+            })?,
+        };
+    }
+    Ok(builder)
 }
 
-async fn basic_auth(
-    req: ServiceRequest,
-    cred: BasicAuth,
-) -> Result<ServiceRequest, (actix_web::error::Error, ServiceRequest)> {
-    let info = req.app_data::<AuthInfo>().unwrap();
-    let mut failed = false;
-    if cred.user_id() != info.user {
-        failed = true;
+/// The `HttpServer` of the plain listeners, and of the HTTPS listeners offering both protocols.
+fn http_server(
+    config: &config::Config,
+    sites: web::Data<vhost::SiteTable>,
+    hsts: Option<String>,
+    server_config: Option<rustls::ServerConfig>,
+    listeners: Vec<listen::Listener>,
+) -> Result<actix_server::Server> {
+    let app = move || main_app(sites.clone(), hsts.clone());
+    let mut server = HttpServer::new(app)
+        .workers(config.workers)
+        .shutdown_timeout(config.shutdown_timeout)
+        .disable_signals();
+    if server_config.is_some() {
+        server = server.on_connect(tls::on_connect(config.client_cert_user));
     }
-    if !failed {
-        if cred.password().is_none() {
-            failed = true;
-        } else {
-            let cached: bool;
-            let provided = cred.password().unwrap();
-            {
-                let cached_pass = info.cached_pass.lock().await;
-                let cached_pass = cached_pass.borrow();
-                cached = !cached_pass.is_empty();
-                failed = cached && cached_pass.as_str() != provided;
+    for listener in listeners {
+        server = match (listener, &server_config) {
+            (
+                listen::Listener::Tcp {
+                    address,
+                    port,
+                    tls: true,
+                },
+                Some(server_config),
+            ) => server.bind_rustls_0_23((address.as_str(), port), server_config.clone())?,
+            (listen::Listener::Tcp { address, port, .. }, _) => {
+                server.bind((address.as_str(), port))?
             }
-            if !cached {
-                let res = bcrypt::verify(provided, &info.hash);
-                failed = !(res.is_ok() && res.unwrap());
-                if !failed {
-                    let cached_pass = info.cached_pass.lock().await;
-                    cached_pass.borrow_mut().push_str(provided);
-                }
+            (listen::Listener::Unix { path, mode }, _) => {
+                server.listen_uds(listen::bind_unix(&path, mode)?)?
             }
-        }
-    }
-    if !failed {
-        return Ok(req);
+            (listen::Listener::InheritedTcp { fd, tls: true, .. }, Some(server_config)) => {
+                server.listen_rustls_0_23(listen::take_inherited(fd), server_config.clone())?
+            }
+            (listen::Listener::InheritedTcp { fd, .. }, _) => {
+                server.listen(listen::take_inherited(fd))?
+            }
+            (listen::Listener::InheritedUnix { fd }, _) => {
+                server.listen_uds(listen::take_inherited(fd))?
+            }
+            (listen::Listener::Redirect { .. } | listen::Listener::InheritedRedirect { .. }, _) => {
+                unreachable!("served by the redirect server")
+            }
+        };
     }
-    let config =
-        actix_web_httpauth::extractors::basic::Config::default().realm("My-Content-Server");
-    Err((AuthenticationError::from(config).into(), req))
+    Ok(server.run())
 }
 
 pub async fn create_server(config: config::Config) -> Result<actix_server::Server> {
-    let mut auth_info = AuthInfo::new("", "");
-
-    let enable_auth = config.user_name.is_some() && config.password_hash.is_some();
-    if enable_auth {
-        auth_info.user = config.user_name.unwrap();
-        auth_info.hash = config.password_hash.unwrap();
+    let sites = web::Data::new(vhost::SiteTable::new(build_sites(&config, None)?));
+    let tls_enabled = config.cert_path.is_some() && config.key_path.is_some();
+    let hsts = (tls_enabled && config.hsts_max_age > 0).then(|| {
+        let mut hsts = format!("max-age={}", config.hsts_max_age);
+        if config.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        hsts
+    });
+    let mut cert_resolver = None;
+    let mut server_config = None;
+    if let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) {
+        let client_ca = config
+            .client_ca_path
+            .as_deref()
+            .map(|ca_path| (ca_path, config.client_auth));
+        let resolver = Arc::new(tls::CertResolver::new(cert_path, key_path, &config.vhosts)?);
+        tls::watch_certs(resolver.clone())?;
+        cert_resolver = Some(resolver.clone());
+        server_config = Some(tls::tls_config(
+            resolver,
+            client_ca,
+            &tls::TlsPolicy::from(&config),
+        )?);
     }
 
-    let app_data = web::Data::new(AppState::new(config.root_dir));
-    let app = move || {
-        let mut app = App::new().configure(app_config).app_data(app_data.clone());
-        if enable_auth {
-            app = app.app_data(auth_info.clone());
-        }
-        app.wrap(Compress::default()).wrap(Condition::new(
-            enable_auth,
-            HttpAuthentication::basic(basic_auth),
-        ))
+    let (redirect_listeners, listeners): (Vec<_>, Vec<_>) = listen::listeners(&config)?
+        .into_iter()
+        .partition(listen::Listener::is_redirect);
+    let https_port = listen::https_port(&listeners).unwrap_or(config.port);
+    let redirect_handle = match redirect_listeners.is_empty() {
+        true => None,
+        false => Some(redirect_server(
+            https_port,
+            redirect_listeners,
+            config.shutdown_timeout,
+        )?),
     };
-
-    let addrs = format!("{}:{}", config.address, config.port);
-    let mut server = HttpServer::new(app).workers(config.workers);
-    if config.cert_path.is_some() && config.key_path.is_some() {
-        server = server.bind_rustls_0_23(
-            addrs,
-            tls_config(&config.cert_path.unwrap(), &config.key_path.unwrap())?,
-        )?;
-    } else {
-        server = server.bind(addrs)?;
+    let single_protocol = config.tls_alpn.len() < 2;
+    let (alpn_listeners, listeners): (Vec<_>, Vec<_>) = listeners
+        .into_iter()
+        .partition(|listener| single_protocol && tls_enabled && listener.is_tls());
+    let mut alpn_builder = match (alpn_listeners.is_empty(), &server_config) {
+        (false, Some(server_config)) => Some(alpn_server(
+            &config,
+            sites.clone(),
+            hsts.clone(),
+            server_config.clone(),
+            alpn_listeners,
+        )?),
+        _ => None,
+    };
+    // with other listeners it runs beside the `HttpServer`, otherwise it is the main server
+    let mut alpn_handle = None;
+    if !listeners.is_empty()
+        && let Some(builder) = alpn_builder.take()
+    {
+        let server = builder.run();
+        alpn_handle = Some(server.handle());
+        tokio::spawn(server);
     }
-    let result = server.run();
 
-    Ok(result)
+    let server = match alpn_builder {
+        Some(builder) => builder.run(),
+        None => http_server(&config, sites.clone(), hsts, server_config, listeners)?,
+    };
+    // all servers stop accepting connections at once and drain their requests together
+    let handles = [Some(server.handle()), redirect_handle, alpn_handle]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let shutdown_timeout = config.shutdown_timeout;
+    tokio::spawn(async move {
+        systemd::shutdown_signal(shutdown_timeout).await;
+        future::join_all(handles.iter().map(|handle| handle.stop(true))).await;
+    });
+    reload::watch_config(config, sites, cert_resolver)?;
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+
+    Ok(server)
 }
@@ -0,0 +1,297 @@
+use actix_web::http::header::Accept;
+use actix_web::mime;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    Type,
+}
+
+impl SortKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+            SortKey::Type => "type",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    Html,
+    Json,
+}
+
+/// The query parameters accepted by directory listings.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ListQuery {
+    pub sort: SortKey,
+    pub order: SortOrder,
+    pub dirs_first: bool,
+    pub filter: Option<String>,
+    pub page: usize,
+    /// Number of entries per page, `0` means no pagination.
+    pub per_page: usize,
+    /// Overrides the format negotiated from the `Accept` header.
+    pub format: Option<ListFormat>,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        Self {
+            sort: SortKey::default(),
+            order: SortOrder::default(),
+            dirs_first: false,
+            filter: None,
+            page: 1,
+            per_page: 0,
+            format: None,
+        }
+    }
+}
+
+impl ListQuery {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, HttpResponse> {
+        let query = web::Query::<ListQuery>::from_query(req.query_string())
+            .map_err(|err| HttpResponse::BadRequest().body(format!("{}", err)))?;
+        let mut query = query.into_inner();
+        if query.filter.as_deref() == Some("") {
+            query.filter = None;
+        }
+        if query.page == 0 {
+            query.page = 1;
+        }
+        Ok(query)
+    }
+
+    /// Whether the listing should be sent as JSON, either because `format=json` is given or
+    /// because the client prefers `application/json` over HTML.
+    pub fn wants_json(&self, req: &HttpRequest) -> bool {
+        if let Some(format) = self.format {
+            return format == ListFormat::Json;
+        }
+        let Some(accept) = req.get_header::<Accept>() else {
+            return false;
+        };
+        for mime in accept.ranked() {
+            if mime == mime::APPLICATION_JSON {
+                return true;
+            }
+            if mime == mime::TEXT_HTML || mime == mime::STAR_STAR {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Returns the filter as a glob pattern, `Ok(None)` when no filter is set.
+    pub fn filter_pattern(&self) -> Result<Option<glob::Pattern>, HttpResponse> {
+        let Some(filter) = &self.filter else {
+            return Ok(None);
+        };
+        glob::Pattern::new(filter).map(Some).map_err(|err| {
+            HttpResponse::BadRequest().body(format!("Invalid filter [{}]: {}", filter, err))
+        })
+    }
+
+    /// The non-default parameters as name/value pairs. The filter and page only apply to the
+    /// current directory, so they are left out when `view_only` is `false`.
+    pub fn params(&self, view_only: bool) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if self.sort != SortKey::default() {
+            params.push(("sort", String::from(self.sort.as_str())));
+        }
+        if self.order == SortOrder::Desc {
+            params.push(("order", String::from("desc")));
+        }
+        if self.dirs_first {
+            params.push(("dirs_first", String::from("true")));
+        }
+        if self.per_page != 0 {
+            params.push(("per_page", self.per_page.to_string()));
+        }
+        if view_only {
+            if let Some(filter) = &self.filter {
+                params.push(("filter", filter.clone()));
+            }
+            if self.page > 1 {
+                params.push(("page", self.page.to_string()));
+            }
+        }
+        params
+    }
+
+    /// Builds a query string from [`Self::params`], with the leading `?`, or an empty string when
+    /// everything is default.
+    pub fn to_query_string(&self, view_only: bool) -> String {
+        let params = self.params(view_only);
+        if params.is_empty() {
+            return String::new();
+        }
+        let params = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+            .collect::<Vec<_>>();
+        format!("?{}", params.join("&"))
+    }
+
+    /// The query for sorting by `key`, ascending unless already sorted ascending by `key`.
+    pub fn sorted_by(&self, key: SortKey) -> Self {
+        let order = if self.sort == key && self.order == SortOrder::Asc {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        };
+        Self {
+            sort: key,
+            order,
+            page: 1,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_page(&self, page: usize) -> Self {
+        Self {
+            page,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_dirs_first(&self, dirs_first: bool) -> Self {
+        Self {
+            dirs_first,
+            page: 1,
+            ..self.clone()
+        }
+    }
+}
+
+/// Compares names the way people expect: case-insensitively, with runs of digits compared by
+/// their numeric value so that `scan2` sorts before `scan10`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_iter = a.chars().flat_map(char::to_lowercase).peekable();
+    let mut b_iter = b.chars().flat_map(char::to_lowercase).peekable();
+    loop {
+        match (a_iter.peek().copied(), b_iter.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) if ca.is_ascii_digit() && cb.is_ascii_digit() => {
+                let mut a_num = String::new();
+                while let Some(c) = a_iter.next_if(char::is_ascii_digit) {
+                    a_num.push(c);
+                }
+                let mut b_num = String::new();
+                while let Some(c) = b_iter.next_if(char::is_ascii_digit) {
+                    b_num.push(c);
+                }
+                let a_trimmed = a_num.trim_start_matches('0');
+                let b_trimmed = b_num.trim_start_matches('0');
+                let ord = a_trimmed
+                    .len()
+                    .cmp(&b_trimmed.len())
+                    .then_with(|| a_trimmed.cmp(b_trimmed))
+                    .then_with(|| a_num.len().cmp(&b_num.len()));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(ca), Some(cb)) => {
+                if ca != cb {
+                    return ca.cmp(&cb);
+                }
+                a_iter.next();
+                b_iter.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "scan10.pdf",
+            "Scan2.pdf",
+            "scan1.pdf",
+            "scan02.pdf",
+            "a",
+            "B",
+            "scan100.pdf",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "a",
+                "B",
+                "scan1.pdf",
+                "Scan2.pdf",
+                "scan02.pdf",
+                "scan10.pdf",
+                "scan100.pdf"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_query() {
+        let req = TestRequest::default().to_http_request();
+        let query = ListQuery::from_request(&req).unwrap();
+        assert_eq!(query, ListQuery::default());
+        assert_eq!(query.to_query_string(true), "");
+
+        let req = TestRequest::default()
+            .uri("/?sort=mtime&order=desc&dirs_first=true&filter=*.pdf&page=2&per_page=50")
+            .to_http_request();
+        let query = ListQuery::from_request(&req).unwrap();
+        assert_eq!(query.sort, SortKey::Mtime);
+        assert_eq!(query.order, SortOrder::Desc);
+        assert!(query.dirs_first);
+        assert_eq!(query.filter, Some(String::from("*.pdf")));
+        assert_eq!(query.page, 2);
+        assert_eq!(query.per_page, 50);
+        assert_eq!(
+            query.to_query_string(true),
+            "?sort=mtime&order=desc&dirs_first=true&per_page=50&filter=%2A.pdf&page=2"
+        );
+        assert_eq!(
+            query.to_query_string(false),
+            "?sort=mtime&order=desc&dirs_first=true&per_page=50"
+        );
+        assert_eq!(
+            query.sorted_by(SortKey::Mtime).to_query_string(true),
+            "?sort=mtime&dirs_first=true&per_page=50&filter=%2A.pdf"
+        );
+        assert_eq!(
+            query.sorted_by(SortKey::Name).to_query_string(false),
+            "?dirs_first=true&per_page=50"
+        );
+
+        let req = TestRequest::default().uri("/?sort=color").to_http_request();
+        assert!(ListQuery::from_request(&req).is_err());
+    }
+}
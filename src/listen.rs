@@ -0,0 +1,321 @@
+use crate::config::Config;
+use crate::systemd;
+use anyhow::{Result, anyhow};
+use std::mem::ManuallyDrop;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+/// A socket the server listens on.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Listener {
+    Tcp {
+        address: String,
+        port: u16,
+        tls: bool,
+    },
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+    /// A TCP socket passed by socket activation, bound to `port`.
+    InheritedTcp {
+        fd: RawFd,
+        port: u16,
+        tls: bool,
+    },
+    InheritedUnix {
+        fd: RawFd,
+    },
+    /// The plain HTTP listener on `http_redirect_port`, which only redirects to HTTPS.
+    Redirect {
+        address: String,
+        port: u16,
+    },
+    /// A TCP socket passed by socket activation, bound to `http_redirect_port`.
+    InheritedRedirect {
+        fd: RawFd,
+    },
+}
+
+impl Listener {
+    pub(crate) fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            Listener::Redirect { .. } | Listener::InheritedRedirect { .. }
+        )
+    }
+
+    /// Whether the listener serves HTTPS.
+    pub(crate) fn is_tls(&self) -> bool {
+        matches!(
+            self,
+            Listener::Tcp { tls: true, .. } | Listener::InheritedTcp { tls: true, .. }
+        )
+    }
+}
+
+/// Reads an integer option of the socket `fd` at the `SOL_SOCKET` level.
+fn socket_option(fd: RawFd, name: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` describe a buffer big enough for an integer option
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            (&raw mut value).cast(),
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(value),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// The listener of a socket passed by socket activation. TCP sockets serve HTTPS when
+/// `cert_path` is set, unless they are bound to `http_redirect_port` and only redirect.
+fn inherited(fd: RawFd, config: &Config) -> Result<Listener> {
+    // a datagram socket or, with `Accept=yes`, a connection has an address too
+    let listening = match (
+        socket_option(fd, libc::SO_TYPE),
+        socket_option(fd, libc::SO_ACCEPTCONN),
+    ) {
+        (Ok(kind), Ok(accepting)) => kind == libc::SOCK_STREAM && accepting != 0,
+        (Err(err), _) | (_, Err(err)) => {
+            return Err(anyhow!(
+                "Descriptor {} passed by systemd is not a socket: {}",
+                fd,
+                err
+            ));
+        }
+    };
+    if !listening {
+        return Err(anyhow!(
+            "Descriptor {} passed by systemd is not a listening stream socket, only `Accept=no` is supported",
+            fd
+        ));
+    }
+    let tls_enabled = config.cert_path.is_some() && config.key_path.is_some();
+    // SAFETY: the descriptor was passed to this process and stays open, it is only borrowed to
+    // read its address.
+    let tcp = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+    if let Ok(addr) = tcp.local_addr() {
+        if tls_enabled && config.http_redirect_port == Some(addr.port()) {
+            return Ok(Listener::InheritedRedirect { fd });
+        }
+        return Ok(Listener::InheritedTcp {
+            fd,
+            port: addr.port(),
+            tls: tls_enabled,
+        });
+    }
+    // SAFETY: as above
+    let unix = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+    match unix.local_addr() {
+        Ok(_) => Ok(Listener::InheritedUnix { fd }),
+        Err(err) => Err(anyhow!(
+            "Descriptor {} passed by systemd is not a listening socket: {}",
+            fd,
+            err
+        )),
+    }
+}
+
+/// Takes over the descriptor of a [`Listener::InheritedTcp`], [`Listener::InheritedUnix`] or
+/// [`Listener::InheritedRedirect`], which is done once for each.
+pub(crate) fn take_inherited<T: FromRawFd>(fd: RawFd) -> T {
+    // SAFETY: the descriptor was passed to this process, and nothing else owns it
+    unsafe { T::from_raw_fd(fd) }
+}
+
+/// The sockets to listen on: those passed by systemd socket activation, or else the `listen`
+/// entries, or else `address` and `port`. With `http_redirect_port`, a plain listener on that
+/// port is added for the address of each HTTPS listener.
+pub(crate) fn listeners(config: &Config) -> Result<Vec<Listener>> {
+    let fds = systemd::listen_fds();
+    if !fds.is_empty() {
+        return fds.into_iter().map(|fd| inherited(fd, config)).collect();
+    }
+    let tls_enabled = config.cert_path.is_some() && config.key_path.is_some();
+    let mut listeners = Vec::new();
+    if config.listen.is_empty() {
+        listeners.push(Listener::Tcp {
+            address: config.address.clone(),
+            port: config.port,
+            tls: tls_enabled,
+        });
+    }
+    for entry in &config.listen {
+        let listener = match (&entry.path, entry.port) {
+            (Some(path), _) => Listener::Unix {
+                path: path.clone(),
+                mode: entry.mode,
+            },
+            (None, Some(port)) => Listener::Tcp {
+                address: entry
+                    .address
+                    .clone()
+                    .unwrap_or_else(|| config.address.clone()),
+                port,
+                tls: entry.tls(tls_enabled),
+            },
+            (None, None) => continue,
+        };
+        listeners.push(listener);
+    }
+    if let Some(redirect_port) = config.http_redirect_port {
+        let mut addresses = Vec::new();
+        for listener in &listeners {
+            if let Listener::Tcp {
+                address, tls: true, ..
+            } = listener
+                && !addresses.contains(address)
+            {
+                addresses.push(address.clone());
+            }
+        }
+        listeners.extend(addresses.into_iter().map(|address| Listener::Redirect {
+            address,
+            port: redirect_port,
+        }));
+    }
+    Ok(listeners)
+}
+
+/// The port plain HTTP requests are redirected to, that of the first HTTPS listener.
+pub(crate) fn https_port(listeners: &[Listener]) -> Option<u16> {
+    listeners.iter().find_map(|listener| match listener {
+        Listener::Tcp {
+            port, tls: true, ..
+        }
+        | Listener::InheritedTcp {
+            port, tls: true, ..
+        } => Some(*port),
+        _ => None,
+    })
+}
+
+/// Binds the Unix socket at `path` and sets the permissions of the socket file to `mode`. A
+/// socket file left by a previous run is replaced, other files are not.
+pub(crate) fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "[{}] exists and is not a Unix socket",
+                path.display()
+            ));
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|err| anyhow!("Binding Unix socket [{}] failed: {}", path.display(), err))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_listeners() {
+        let toml = "cert_path = \"res_dir/cert.pem\"\nkey_path = \"res_dir/key.pem\"\n\
+                    http_redirect_port = 8080\n\
+                    [[listen]]\naddress = \"127.0.0.1\"\nport = 8443\n\
+                    [[listen]]\naddress = \"::1\"\nport = 8443\n\
+                    [[listen]]\nport = 8000\ntls = false\n\
+                    [[listen]]\npath = \"my_cont_srv.sock\"\nmode = 0o660\n";
+        let dir = TempDir::new("listen");
+        let file = dir.join("config.toml");
+        std::fs::write(&file, toml).unwrap();
+        let cli = config::parse_cli_from(["my-cont-srv", "-c", file.to_str().unwrap()]);
+        let config = config::get_config(cli).unwrap();
+        let tcp = |address: &str, port, tls| Listener::Tcp {
+            address: String::from(address),
+            port,
+            tls,
+        };
+        let listeners = listeners(&config).unwrap();
+        assert_eq!(
+            listeners,
+            vec![
+                tcp("127.0.0.1", 8443, true),
+                tcp("::1", 8443, true),
+                tcp(config::DEFAULT_ADDRESS, 8000, false),
+                Listener::Unix {
+                    path: PathBuf::from("my_cont_srv.sock"),
+                    mode: Some(0o660)
+                },
+                Listener::Redirect {
+                    address: String::from("127.0.0.1"),
+                    port: 8080
+                },
+                Listener::Redirect {
+                    address: String::from("::1"),
+                    port: 8080
+                },
+            ]
+        );
+        assert_eq!(https_port(&listeners), Some(8443));
+
+        let socket = dir.join("my_cont_srv.sock");
+        drop(bind_unix(&socket, Some(0o600)).unwrap());
+        // the socket file left behind is replaced
+        bind_unix(&socket, Some(0o660)).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(bind_unix(&file, None).is_err());
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&tcp);
+        assert_eq!(
+            inherited(fd, &config).unwrap(),
+            Listener::InheritedTcp {
+                fd,
+                port,
+                tls: true
+            }
+        );
+        let unix = UnixListener::bind(dir.join("inherited.sock")).unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&unix);
+        assert_eq!(
+            inherited(fd, &config).unwrap(),
+            Listener::InheritedUnix { fd }
+        );
+        let not_socket = std::fs::File::open(&file).unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&not_socket);
+        assert!(inherited(fd, &config).is_err());
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&udp);
+        assert!(inherited(fd, &config).is_err());
+        // what `Accept=yes` passes
+        let conn = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&conn);
+        let err = inherited(fd, &config).unwrap_err().to_string();
+        assert!(err.contains("Accept=no"), "{}", err);
+
+        std::fs::write(
+            &file,
+            "[[listen]]\npath = \"a.sock\"\ntls = true\n[[listen]]\nport = 80\npath = \"b.sock\"\n",
+        )
+        .unwrap();
+        let cli = config::parse_cli_from(["my-cont-srv", "-c", file.to_str().unwrap()]);
+        let err = config::get_config(cli).err().unwrap().to_string();
+        assert_eq!(
+            err,
+            "listen entry 1: TLS is not supported on Unix sockets\n\
+             listen entry 2: exactly one of `port` and `path` is needed"
+        );
+    }
+}
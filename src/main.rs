@@ -1,14 +1,62 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use my_cont_srv::config::{Command, HashScheme};
+use my_cont_srv::{auth, check, share, tls};
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    let cli = my_cont_srv::config::parse_cli();
+    let mut cli = my_cont_srv::config::parse_cli();
 
     if let Some(hash_password) = cli.hash_password {
-        let hash = bcrypt::hash(hash_password, bcrypt::DEFAULT_COST)?;
+        let hash = auth::hash_password(&hash_password, HashScheme::Bcrypt)?;
         println!("{}", hash);
         return Ok(());
     }
+    if cli.print_config {
+        my_cont_srv::config::print_config(&cli)?;
+        return Ok(());
+    }
+
+    match cli.command.take() {
+        Some(Command::User { file, action }) => {
+            let file = match file {
+                Some(file) => file,
+                None => my_cont_srv::config::get_config(cli)?
+                    .users_file
+                    .ok_or(anyhow!("No users file given, use `--file` or `users_file`"))?,
+            };
+            auth::run_user_command(&file, action)?;
+            return Ok(());
+        }
+        Some(Command::Share {
+            path,
+            host,
+            expires_in,
+            max_downloads,
+            password,
+            base_url,
+        }) => {
+            let config = my_cont_srv::config::get_config(cli)?;
+            share::run_share_command(
+                &config,
+                host.as_deref(),
+                &path,
+                expires_in,
+                max_downloads,
+                password.as_deref(),
+                &base_url,
+            )?;
+            return Ok(());
+        }
+        Some(Command::GenCert(args)) => {
+            tls::run_gen_cert(&args)?;
+            return Ok(());
+        }
+        Some(Command::CheckConfig) => {
+            check::run_check_config(cli)?;
+            return Ok(());
+        }
+        None => {}
+    }
 
     let config = my_cont_srv::config::get_config(cli)?;
     let server = my_cont_srv::create_server(config).await?;
@@ -0,0 +1,209 @@
+use crate::config::SymlinkPolicy;
+use actix_web::HttpResponse;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+#[derive(Debug, PartialEq)]
+pub enum ResolveError {
+    BadRequest,
+    Forbidden,
+    NotFound,
+}
+
+impl ResolveError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            ResolveError::BadRequest => HttpResponse::BadRequest().body("Invalid request path"),
+            ResolveError::Forbidden => HttpResponse::Forbidden().body("Access denied"),
+            ResolveError::NotFound => HttpResponse::NotFound().body("Resource not found"),
+        }
+    }
+}
+
+/// Splits a decoded request path into plain file name segments. Both `/` and `\` are treated
+/// as separators, empty and `.` segments are dropped, and anything that could climb out of or
+/// replace the base directory (`..`, drive prefixes, roots) is rejected.
+fn split_segments(rel_path: &str) -> Result<Vec<&str>, ResolveError> {
+    if rel_path.contains('\0') {
+        return Err(ResolveError::BadRequest);
+    }
+    let mut segments = Vec::new();
+    for seg in rel_path.split(['/', '\\']) {
+        if seg.is_empty() || seg == "." {
+            continue;
+        }
+        let mut components = Path::new(seg).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => segments.push(seg),
+            _ => return Err(ResolveError::Forbidden),
+        }
+    }
+    Ok(segments)
+}
+
+/// The canonical form of a request path, `/` followed by its segments joined with `/`, which is
+/// what access control rules are matched against.
+pub fn normalize(rel_path: &str) -> Result<String, ResolveError> {
+    Ok(format!("/{}", split_segments(rel_path)?.join("/")))
+}
+
+/// Resolves a path relative to the contents root and makes sure the result stays inside it.
+///
+/// `rel_path` must already be percent/base64 decoded. The returned path is the root joined with
+/// the validated segments, so file names (and thereby guessed MIME types) are preserved even when
+/// symlinks are followed.
+pub async fn resolve(
+    root: &Path,
+    rel_path: &str,
+    policy: SymlinkPolicy,
+) -> Result<PathBuf, ResolveError> {
+    let segments = split_segments(rel_path)?;
+    let Ok(canon_root) = fs::canonicalize(root).await else {
+        return Err(ResolveError::NotFound);
+    };
+
+    let mut path = root.to_path_buf();
+    for seg in segments {
+        path.push(seg);
+        if policy == SymlinkPolicy::Deny {
+            let Ok(meta) = fs::symlink_metadata(&path).await else {
+                return Err(ResolveError::NotFound);
+            };
+            if meta.file_type().is_symlink() {
+                return Err(ResolveError::Forbidden);
+            }
+        }
+    }
+
+    let Ok(canon_path) = fs::canonicalize(&path).await else {
+        return Err(ResolveError::NotFound);
+    };
+    if policy != SymlinkPolicy::Follow && !canon_path.starts_with(&canon_root) {
+        return Err(ResolveError::Forbidden);
+    }
+
+    Ok(path)
+}
+
+/// The normalized path of `path` relative to `root` once symlinks are followed, `None` when it
+/// leads outside the root or is not valid UTF-8.
+pub async fn root_relative(root: &Path, path: &Path) -> Option<String> {
+    let canon_root = fs::canonicalize(root).await.ok()?;
+    let canon_path = fs::canonicalize(path).await.ok()?;
+    let rel_path = canon_path.strip_prefix(&canon_root).ok()?;
+    let segments = rel_path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("/{}", segments.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::{AppState, app_config, fs_proc::fs_get};
+    use ::base64::Engine;
+    use actix_http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, web};
+    use base64::engine::general_purpose as base64;
+
+    #[actix_web::test]
+    async fn test_resolve_plain() {
+        let path = resolve(Path::new("."), "/src/lib.rs", SymlinkPolicy::default()).await;
+        assert_eq!(path, Ok(PathBuf::from("./src/lib.rs")));
+        let path = resolve(Path::new("."), "src\\.\\lib.rs", SymlinkPolicy::default()).await;
+        assert_eq!(path, Ok(PathBuf::from("./src/lib.rs")));
+        let path = resolve(Path::new("."), "/", SymlinkPolicy::default()).await;
+        assert_eq!(path, Ok(PathBuf::from(".")));
+        let path = resolve(Path::new("."), "/non_exist", SymlinkPolicy::default()).await;
+        assert_eq!(path, Err(ResolveError::NotFound));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(""), Ok(String::from("/")));
+        assert_eq!(normalize("//a\\./b/"), Ok(String::from("/a/b")));
+        assert_eq!(normalize("/a/../b"), Err(ResolveError::Forbidden));
+    }
+
+    #[actix_web::test]
+    async fn test_resolve_traversal() {
+        for rel_path in [
+            "../Cargo.toml",
+            "/src/../../Cargo.toml",
+            "..\\Cargo.toml",
+            "src\\..\\..\\Cargo.toml",
+        ] {
+            let path = resolve(Path::new("src"), rel_path, SymlinkPolicy::Follow).await;
+            assert_eq!(path, Err(ResolveError::Forbidden), "{}", rel_path);
+        }
+        let path = resolve(Path::new("src"), "lib.rs\0", SymlinkPolicy::Follow).await;
+        assert_eq!(path, Err(ResolveError::BadRequest));
+    }
+
+    #[actix_web::test]
+    async fn test_fs_get_encoded_traversal() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from("src")));
+        for uri in [
+            "/%2e%2e/Cargo.toml",
+            "/%2E%2E%2FCargo.toml",
+            "/..%5CCargo.toml",
+            "/%2e%2e%5c%2e%2e%5cCargo.toml",
+        ] {
+            let req = TestRequest::default().uri(uri).to_http_request();
+            let resp = fs_get(req, app_data.clone()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_epub_traversal() {
+        let app_data = web::Data::new(AppState::new(PathBuf::from("src")));
+        let app = init_service(App::new().configure(app_config).app_data(app_data)).await;
+        for uri in [
+            "/epub_toc/%2e%2e/res_dir/v2.epub",
+            "/epub_toc/..%5Cres_dir%5Cv2.epub",
+        ] {
+            let req = TestRequest::default().uri(uri).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+        for payload in [
+            "../res_dir/v2.epub",
+            "..\\res_dir\\v2.epub",
+            "/../res_dir/v2.epub",
+        ] {
+            let uri = format!(
+                "/epub_cont/{}/OEBPS/valentinhauy11.html",
+                base64::URL_SAFE_NO_PAD.encode(payload)
+            );
+            let req = TestRequest::default().uri(&uri).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", payload);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_resolve_symlink_policy() {
+        let base = TempDir::new("symlink");
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(base.join("outside.txt"), "outside").unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        std::os::unix::fs::symlink(base.join("outside.txt"), root.join("out_link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside.txt"), root.join("in_link.txt")).unwrap();
+
+        let res = resolve(&root, "out_link.txt", SymlinkPolicy::Follow).await;
+        assert_eq!(res, Ok(root.join("out_link.txt")));
+        let res = resolve(&root, "out_link.txt", SymlinkPolicy::FollowWithinRoot).await;
+        assert_eq!(res, Err(ResolveError::Forbidden));
+        let res = resolve(&root, "in_link.txt", SymlinkPolicy::FollowWithinRoot).await;
+        assert_eq!(res, Ok(root.join("in_link.txt")));
+        let res = resolve(&root, "in_link.txt", SymlinkPolicy::Deny).await;
+        assert_eq!(res, Err(ResolveError::Forbidden));
+        let res = resolve(&root, "inside.txt", SymlinkPolicy::Deny).await;
+        assert_eq!(res, Ok(root.join("inside.txt")));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::build_sites;
+use crate::config::{self, Config};
+use crate::tls::{self, CertResolver};
+use crate::vhost::SiteTable;
+use actix_web::web;
+use anyhow::Result;
+use notify::{RecursiveMode, Watcher};
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+
+/// The settings only read when the server starts which differ between `running` and `new`.
+fn restart_needed(running: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    macro_rules! compare {
+        ($($field:ident),*) => {
+            $(
+                if running.$field != new.$field {
+                    changed.push(stringify!($field));
+                }
+            )*
+        };
+    }
+    compare!(
+        address,
+        port,
+        workers,
+        client_ca_path,
+        client_auth,
+        client_cert_user,
+        tls_min_version,
+        tls_cipher_suites,
+        tls_alpn,
+        tls_session_tickets,
+        tls_session_cache,
+        hsts_max_age,
+        hsts_include_subdomains,
+        http_redirect_port,
+        listen,
+        watch_config,
+        shutdown_timeout
+    );
+    if running.cert_path.is_some() != new.cert_path.is_some() {
+        changed.push("cert_path");
+    }
+    // the login sessions are kept, and so are their timeouts
+    if running.form_login && new.form_login {
+        compare!(session_idle_timeout, session_remember_days);
+    }
+    changed
+}
+
+struct Reloader {
+    /// The config the server was started with, for the settings a reload can not change.
+    started: Config,
+    current: Config,
+    sites: web::Data<SiteTable>,
+    resolver: Option<Arc<CertResolver>>,
+}
+
+impl Reloader {
+    /// Reads the config again and swaps in the sites and certificates made from it, or keeps
+    /// everything as it was when any of them fails. Returns the changed settings which need a
+    /// restart.
+    fn reload(&mut self) -> Result<Vec<&'static str>> {
+        let config = config::get_config(self.current.cli.clone())?;
+        let vhosts = build_sites(&config, Some((&self.current, &self.sites.current())))?;
+        if let (Some(resolver), Some(cert_path), Some(key_path)) =
+            (&self.resolver, &config.cert_path, &config.key_path)
+        {
+            resolver.replace(cert_path, key_path, &config.vhosts)?;
+        }
+        self.sites.replace(vhosts);
+        let restart = restart_needed(&self.started, &config);
+        self.current = config;
+        Ok(restart)
+    }
+}
+
+/// Reloads the config on SIGHUP, and when the config file changes with `watch_config`. Users,
+/// access rules, roots, caches, templates and certificates are replaced, requests already being
+/// served finish with the previous ones.
+pub fn watch_config(
+    config: Config,
+    sites: web::Data<SiteTable>,
+    resolver: Option<Arc<CertResolver>>,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let watcher = match &config.cli.config_file {
+        Some(file) if config.watch_config => {
+            let file = std::path::absolute(file)?;
+            let watch_tx = tx.clone();
+            let watched = file.clone();
+            let mut watcher =
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    if let Ok(event) = event
+                        && !event.kind.is_access()
+                        && event.paths.contains(&watched)
+                    {
+                        let _ = watch_tx.send(());
+                    }
+                })?;
+            // the directory is watched, as editors often replace the file
+            if let Some(dir) = file.parent() {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+            Some(watcher)
+        }
+        _ => None,
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut reloader = Reloader {
+        started: config.clone(),
+        current: config,
+        sites,
+        resolver,
+    };
+    tokio::spawn(async move {
+        // keeps the watcher alive as long as the task
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(tls::SETTLE_DELAY).await;
+            while rx.try_recv().is_ok() {}
+            // reading and parsing the files is kept off the runtime
+            let Ok((returned, res)) = web::block(move || {
+                let res = reloader.reload();
+                (reloader, res)
+            })
+            .await
+            else {
+                eprintln!("Reloading the config failed, no more reloads until a restart");
+                break;
+            };
+            reloader = returned;
+            match res {
+                Ok(restart) if restart.is_empty() => eprintln!("Reloaded the config"),
+                Ok(restart) => eprintln!(
+                    "Reloaded the config, restart to apply the changes of: {}",
+                    restart.join(", ")
+                ),
+                Err(err) => {
+                    eprintln!("Keeping the previous config: {:#}", err);
+                    // SIGHUP is also how renewed certificates are announced
+                    if let Some(resolver) = &reloader.resolver {
+                        resolver.clone().reload_blocking().await;
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("reload");
+        let file = dir.join("config.toml");
+        let write_config = |root_dir: &str, port: u16, share_secret: &str, hash: &str| {
+            let toml = format!(
+                "port = {}\nroot_dir = \"{}\"\nform_login = true\nshare_secret = \"{}\"\n\
+                 [[users]]\nname = \"alice\"\npassword_hash = \"{}\"\n",
+                port, root_dir, share_secret, hash
+            );
+            std::fs::write(&file, toml).unwrap();
+        };
+        let hash = bcrypt::hash("alicepass", 4).unwrap();
+        write_config(".", 1131, "a", &hash);
+        let cli = config::parse_cli_from(["my-cont-srv", "-c", file.to_str().unwrap()]);
+        let config = config::get_config(cli).unwrap();
+        let sites = web::Data::new(SiteTable::new(build_sites(&config, None).unwrap()));
+        let mut reloader = Reloader {
+            started: config.clone(),
+            current: config,
+            sites: sites.clone(),
+            resolver: None,
+        };
+        let old = sites.current();
+        let old = old.default_site();
+        let old_auth = old.auth_info.as_ref().unwrap();
+        let session = old_auth.sessions().unwrap().create("alice", &hash, false);
+        let ip = "192.0.2.1".parse().ok();
+        for _ in 0..config::DEFAULT_AUTH_MAX_FAILURES {
+            old_auth.throttle().record_failure(ip, None);
+        }
+
+        write_config("res_dir", 1132, "a", &hash);
+        assert_eq!(reloader.reload().unwrap(), vec!["port"]);
+        let new = sites.current();
+        let new = new.default_site();
+        assert_eq!(new.app_state.root_dir, PathBuf::from("res_dir"));
+        // logins and download counts survive
+        let sessions = |site: &crate::vhost::Site| site.auth_info.as_ref()?.session_store();
+        assert!(Arc::ptr_eq(
+            &sessions(old).unwrap(),
+            &sessions(new).unwrap()
+        ));
+        assert!(Arc::ptr_eq(&old.app_state.shares, &new.app_state.shares));
+        // and so do lockouts
+        let new_auth = new.auth_info.as_ref().unwrap();
+        assert!(new_auth.throttle().locked(ip, None).is_some());
+
+        // an invalid config changes nothing
+        std::fs::write(&file, "port = \"x\"\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(
+            sites.current().default_site().app_state.root_dir,
+            PathBuf::from("res_dir")
+        );
+
+        let users = |site: &crate::vhost::Site| site.auth_info.as_ref().unwrap().users().clone();
+        let store = new_auth.sessions().unwrap();
+        assert!(store.validate(&session, &users(new)).is_some());
+
+        // links signed with the previous secret are invalid anyway
+        let new_hash = bcrypt::hash("newpass", 4).unwrap();
+        write_config("res_dir", 1131, "b", &new_hash);
+        assert!(reloader.reload().unwrap().is_empty());
+        let newer = sites.current();
+        assert!(!Arc::ptr_eq(
+            &new.app_state.shares,
+            &newer.default_site().app_state.shares
+        ));
+        // changing the password ends the sessions of the user
+        assert!(
+            store
+                .validate(&session, &users(newer.default_site()))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use crate::AppState;
+use crate::auth::{AuthInfo, Identity, Verdict};
+use crate::tls;
+use ::base64::Engine;
+use actix_web::cookie::{Cookie, SameSite, time};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Uri;
+use actix_web::http::header::{LOCATION, ORIGIN};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, web};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose as base64;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+pub const SESSION_COOKIE: &str = "my_cont_srv_session";
+
+struct Session {
+    user: String,
+    /// Digest of the user's password hash at login, the session ends when the hash changes.
+    cred: [u8; 32],
+    created: SystemTime,
+    last_seen: SystemTime,
+    remember: bool,
+}
+
+/// Server-side login sessions. The cookie carries a random session id signed with a per-process
+/// key, the session itself only exists in memory, so logging out or restarting the server
+/// revokes it.
+pub struct SessionStore {
+    key: [u8; 32],
+    idle_timeout: Duration,
+    remember_age: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration, remember_age: Duration) -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            key,
+            idle_timeout,
+            remember_age,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn mac(&self, id: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key size works");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    fn is_expired(&self, session: &Session, now: SystemTime) -> bool {
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        let idle_timeout = if session.remember {
+            self.remember_age
+        } else {
+            self.idle_timeout
+        };
+        elapsed(session.last_seen) > idle_timeout || elapsed(session.created) > self.remember_age
+    }
+
+    /// Starts a session for `user`, whose password hash is `hash`, and returns the cookie value
+    /// identifying it.
+    pub fn create(&self, user: &str, hash: &str, remember: bool) -> String {
+        let mut id = [0; 32];
+        OsRng.fill_bytes(&mut id);
+        let id = base64::URL_SAFE_NO_PAD.encode(id);
+        let sig = base64::URL_SAFE_NO_PAD.encode(self.mac(&id).finalize().into_bytes());
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !self.is_expired(session, now));
+        sessions.insert(
+            id.clone(),
+            Session {
+                user: String::from(user),
+                cred: Sha256::digest(hash).into(),
+                created: now,
+                last_seen: now,
+                remember,
+            },
+        );
+        format!("{}.{}", id, sig)
+    }
+
+    /// The session id of a cookie value whose signature is valid.
+    fn session_id<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, sig) = value.split_once('.')?;
+        let sig = base64::URL_SAFE_NO_PAD.decode(sig).ok()?;
+        self.mac(id).verify_slice(&sig).ok()?;
+        Some(id)
+    }
+
+    /// Returns the user of a live session and restarts its idle timer. Sessions of users no
+    /// longer in `users`, or whose password hash changed, are ended, so that a reloaded config
+    /// revokes them.
+    pub fn validate(&self, value: &str, users: &HashMap<String, String>) -> Option<String> {
+        let id = self.session_id(value)?;
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)?;
+        let current = users
+            .get(&session.user)
+            .is_some_and(|hash| <[u8; 32]>::from(Sha256::digest(hash)) == session.cred);
+        if !current || self.is_expired(session, now) {
+            sessions.remove(id);
+            return None;
+        }
+        session.last_seen = now;
+        Some(session.user.clone())
+    }
+
+    pub fn revoke(&self, value: &str) {
+        if let Some(id) = self.session_id(value) {
+            self.sessions.lock().unwrap().remove(id);
+        }
+    }
+
+    fn cookie(&self, value: String, remember: bool, secure: bool) -> Cookie<'static> {
+        let mut cookie = Cookie::build(SESSION_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(secure)
+            .finish();
+        if remember {
+            let max_age = self.remember_age.as_secs().min(i64::MAX as u64) as i64;
+            cookie.set_max_age(time::Duration::seconds(max_age));
+        }
+        cookie
+    }
+}
+
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+/// Only local paths are accepted as the page to return to after logging in. Browsers drop
+/// whitespace and control characters from a `Location` and some clients decode `%2F`, so the
+/// value must consist of visible ASCII and be local both as given and percent-decoded.
+fn local_target(next: Option<&str>) -> &str {
+    match next {
+        Some(next)
+            if next.chars().all(|c| c.is_ascii_graphic())
+                && is_local_path(next)
+                && urlencoding::decode(next).is_ok_and(|decoded| {
+                    is_local_path(&decoded) && !decoded.chars().any(char::is_control)
+                }) =>
+        {
+            next
+        }
+        _ => "/",
+    }
+}
+
+#[derive(Serialize)]
+struct LoginCtx<'a> {
+    next: &'a str,
+    user: &'a str,
+    error: Option<&'a str>,
+}
+
+fn login_page(app_state: &AppState, ctx: LoginCtx, mut resp: HttpResponseBuilder) -> HttpResponse {
+    match app_state.templates.render("login.html", ctx) {
+        Ok(body) => resp.content_type("text/html; charset=utf-8").body(body),
+        Err(err) => HttpResponse::InternalServerError()
+            .body(format!("Rendering login page failed: {:?}", err)),
+    }
+}
+
+/// Refuses a `POST` another site made the browser send, such as one signing the visitor in as
+/// someone else. Browsers tell where a request comes from with `Sec-Fetch-Site`, or at least
+/// `Origin`, which must then be this server. Requests with neither come from other clients,
+/// such as scripts creating share links, and are let through.
+pub(crate) fn check_same_origin(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default())
+    };
+    let same_origin = match (header("sec-fetch-site"), header(ORIGIN.as_str())) {
+        (Some(site), _) => site == "same-origin" || site == "none",
+        (None, Some(origin)) => origin.parse::<Uri>().is_ok_and(|origin| {
+            let scheme = if tls::is_https(req) { "https" } else { "http" };
+            origin.scheme_str() == Some(scheme)
+                && origin.authority().is_some_and(|authority| {
+                    authority
+                        .as_str()
+                        .eq_ignore_ascii_case(req.connection_info().host())
+                })
+        }),
+        (None, None) => true,
+    };
+    match same_origin {
+        true => Ok(()),
+        false => Err(HttpResponse::Forbidden().body("Cross-site request refused")),
+    }
+}
+
+fn session_store(req: &HttpRequest) -> Option<&SessionStore> {
+    req.app_data::<AuthInfo>()?.sessions()
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+pub async fn login_get(
+    req: HttpRequest,
+    query: web::Query<LoginQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if session_store(&req).is_none() {
+        return HttpResponse::NotFound().body("Resource not found");
+    }
+    let ctx = LoginCtx {
+        next: local_target(query.next.as_deref()),
+        user: "",
+        error: None,
+    };
+    login_page(&app_state, ctx, HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    user: String,
+    password: String,
+    remember: Option<String>,
+    next: Option<String>,
+}
+
+pub async fn login_post(
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let (Some(info), Some(store)) = (req.app_data::<AuthInfo>(), session_store(&req)) else {
+        return HttpResponse::NotFound().body("Resource not found");
+    };
+    if let Err(resp) = check_same_origin(&req) {
+        return resp;
+    }
+    let next = local_target(form.next.as_deref());
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let error = match info.verify(&form.user, &form.password, ip).await {
+        Verdict::Verified => None,
+        Verdict::Denied => Some((
+            String::from("Invalid user name or password"),
+            HttpResponse::Unauthorized(),
+        )),
+        Verdict::LockedOut(wait) => Some((
+            format!(
+                "Too many failed attempts, try again in {} seconds",
+                wait.as_secs().max(1)
+            ),
+            HttpResponse::TooManyRequests(),
+        )),
+    };
+    if let Some((error, resp)) = error {
+        let ctx = LoginCtx {
+            next,
+            user: &form.user,
+            error: Some(&error),
+        };
+        return login_page(&app_state, ctx, resp);
+    }
+    let remember = form.remember.is_some();
+    let Some(hash) = info.user_hash(&form.user) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let value = store.create(&form.user, hash, remember);
+    let secure = tls::is_https(&req);
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, next))
+        .cookie(store.cookie(value, remember, secure))
+        .finish()
+}
+
+pub async fn logout(req: HttpRequest) -> HttpResponse {
+    let Some(store) = session_store(&req) else {
+        return HttpResponse::NotFound().body("Resource not found");
+    };
+    if let Err(resp) = check_same_origin(&req) {
+        return resp;
+    }
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        store.revoke(cookie.value());
+    }
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .cookie(removal)
+        .finish()
+}
+
+/// The identity of a request carrying a live session cookie of a current user.
+pub fn identify(req: &ServiceRequest, info: &AuthInfo) -> Option<Identity> {
+    let store = info.sessions()?;
+    let cookie = req.cookie(SESSION_COOKIE)?;
+    let user = store.validate(cookie.value(), info.users())?;
+    Some(Identity {
+        user,
+        session: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_config;
+    use crate::auth::authenticate;
+    use actix_http::StatusCode;
+    use actix_web::http::header::{ACCEPT, SET_COOKIE};
+    use actix_web::test::{TestRequest, call_and_read_body, call_service, init_service};
+    use actix_web::{App, middleware::from_fn};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_session_store() {
+        let users = HashMap::from([
+            (String::from("alice"), String::from("hash-a")),
+            (String::from("bob"), String::from("hash-b")),
+        ]);
+        let store = SessionStore::new(Duration::from_millis(50), Duration::from_secs(60));
+        let value = store.create("alice", "hash-a", false);
+        assert_eq!(store.validate(&value, &users), Some(String::from("alice")));
+        // a forged or tampered cookie is rejected
+        let (id, _) = value.split_once('.').unwrap();
+        assert_eq!(store.validate(id, &users), None);
+        assert_eq!(store.validate(&format!("{}.AAAA", id), &users), None);
+        let other = SessionStore::new(Duration::from_secs(60), Duration::from_secs(60));
+        assert_eq!(other.validate(&value, &users), None);
+
+        store.revoke(&value);
+        assert_eq!(store.validate(&value, &users), None);
+
+        let value = store.create("alice", "hash-a", false);
+        let remembered = store.create("bob", "hash-b", true);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.validate(&value, &users), None);
+        assert_eq!(
+            store.validate(&remembered, &users),
+            Some(String::from("bob"))
+        );
+
+        // removing a user or changing their password ends their sessions
+        let value = store.create("alice", "hash-a", true);
+        let changed = HashMap::from([(String::from("alice"), String::from("hash-a2"))]);
+        assert_eq!(store.validate(&remembered, &changed), None);
+        assert_eq!(store.validate(&value, &changed), None);
+        assert_eq!(store.validate(&value, &users), None);
+    }
+
+    #[test]
+    fn test_local_target() {
+        assert_eq!(local_target(Some("/a/b?c=d")), "/a/b?c=d");
+        assert_eq!(local_target(Some("//evil.com/")), "/");
+        assert_eq!(local_target(Some("/\\evil.com/")), "/");
+        assert_eq!(local_target(Some("https://evil.com/")), "/");
+        assert_eq!(local_target(Some("/\t/evil.com")), "/");
+        assert_eq!(local_target(Some("/\n/evil.com")), "/");
+        assert_eq!(local_target(Some("/ /evil.com")), "/");
+        assert_eq!(local_target(Some("/%2F/evil.com")), "/");
+        assert_eq!(local_target(Some("/%5Cevil.com")), "/");
+        assert_eq!(
+            local_target(Some("/%E7%9B%AE%E5%BD%95/")),
+            "/%E7%9B%AE%E5%BD%95/"
+        );
+        assert_eq!(local_target(None), "/");
+    }
+
+    #[actix_web::test]
+    async fn test_form_login() {
+        let users = HashMap::from([(String::from("alice"), bcrypt::hash("alicepass", 4).unwrap())]);
+        let auth_info = AuthInfo::new(users).with_sessions(Arc::new(SessionStore::new(
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        )));
+        let app_data = web::Data::new(AppState::new(PathBuf::from(".")));
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(app_data)
+                .app_data(auth_info)
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+
+        // browsers are sent to the login page, other clients get a basic auth challenge
+        let req = TestRequest::default()
+            .uri("/src/?sort=size")
+            .insert_header((ACCEPT, "text/html"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/login?next=%2Fsrc%2F%3Fsort%3Dsize"
+        );
+        let req = TestRequest::default().uri("/src/").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::default().uri("/login?next=/src/").to_request();
+        let body = call_and_read_body(&app, req).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(r#"<input type="hidden" name="next" value="/src/">"#));
+
+        let req = TestRequest::post()
+            .uri("/login")
+            .set_form([("user", "alice"), ("password", "wrong"), ("next", "/src/")])
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().get(SET_COOKIE).is_none());
+
+        // another site can not sign the visitor in, as its own user for instance
+        for (name, value, status) in [
+            ("Sec-Fetch-Site", "cross-site", StatusCode::FORBIDDEN),
+            ("Sec-Fetch-Site", "same-site", StatusCode::FORBIDDEN),
+            ("Origin", "http://evil.example", StatusCode::FORBIDDEN),
+            ("Origin", "null", StatusCode::FORBIDDEN),
+            ("Origin", "https://localhost:8080", StatusCode::FORBIDDEN),
+            ("Origin", "http://localhost:8080", StatusCode::SEE_OTHER),
+            ("Sec-Fetch-Site", "same-origin", StatusCode::SEE_OTHER),
+        ] {
+            let req = TestRequest::post()
+                .uri("/login")
+                .insert_header((name, value))
+                .set_form([("user", "alice"), ("password", "alicepass")])
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}: {}", name, value);
+        }
+
+        let req = TestRequest::post()
+            .uri("/login")
+            .set_form([
+                ("user", "alice"),
+                ("password", "alicepass"),
+                ("remember", "on"),
+                ("next", "/src/"),
+            ])
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/src/");
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert!(cookie.http_only().unwrap());
+        assert!(cookie.max_age().is_some());
+
+        let req = TestRequest::default()
+            .uri("/src/")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("lib.rs"));
+        assert!(body.contains(r#"<form class="logout" method="post" action="/logout">"#));
+
+        let req = TestRequest::post()
+            .uri("/logout")
+            .insert_header(("Sec-Fetch-Site", "cross-site"))
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = TestRequest::post()
+            .uri("/logout")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/login");
+
+        // the session is revoked on the server, not only removed from the browser
+        let req = TestRequest::default()
+            .uri("/src/")
+            .cookie(cookie)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,937 @@
+use crate::auth::{AuthInfo, Identity};
+use crate::config::{self, Perm};
+use crate::{AppState, path_res, session, tls};
+use ::base64::Engine;
+use actix_web::cookie::{Cookie, SameSite, time};
+use actix_web::dev::ServiceRequest;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, web};
+use anyhow::{Result, anyhow};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose as base64;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::cell::Ref;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SHARE_PARAM: &str = "share";
+pub const SHARE_COOKIE: &str = "my_cont_srv_share";
+pub const SHARE_READ_COOKIE: &str = "my_cont_srv_share_read";
+
+/// What a share token grants, signed into the token itself.
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    id: String,
+    path: String,
+    exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max: Option<u64>,
+    /// HMAC of the password, present when the share is password protected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pw: Option<String>,
+}
+
+/// Access granted by a valid share token, stored in the request extensions.
+pub struct ShareGrant {
+    id: String,
+    path: String,
+    exp: u64,
+    max: Option<u64>,
+    pw: Option<String>,
+    /// Whether the share has a password that has not been given yet.
+    locked: bool,
+}
+
+impl ShareGrant {
+    /// Whether the normalized `path` is the shared path or inside it.
+    pub fn covers(&self, path: &str) -> bool {
+        self.path == "/"
+            || path == self.path
+            || path
+                .strip_prefix(self.path.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The random id of the share link.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShareError {
+    Invalid,
+    Expired,
+}
+
+impl ShareError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            ShareError::Invalid => HttpResponse::Forbidden().body("Invalid share link"),
+            ShareError::Expired => HttpResponse::Gone().body("Share link expired"),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The downloads through one share link.
+#[derive(Default)]
+struct Downloads {
+    count: u64,
+    /// The bytes of each file, by ETag, served or being served since its download was last
+    /// counted.
+    served: HashMap<String, Arc<AtomicU64>>,
+}
+
+/// Bytes of a shared file reserved for a response. Those not sent by the time it is dropped are
+/// given back, so that an interrupted download can be resumed without counting again.
+pub(crate) struct Reservation {
+    served: Arc<AtomicU64>,
+    unsent: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.served.fetch_sub(self.unsent, Ordering::Relaxed);
+    }
+}
+
+/// Counts the bytes of `body` as sent for `reservation`.
+pub(crate) fn track<S>(
+    body: S,
+    mut reservation: Option<Reservation>,
+) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    body.map(move |chunk| {
+        if let (Ok(bytes), Some(reservation)) = (&chunk, &mut reservation) {
+            reservation.unsent = reservation.unsent.saturating_sub(bytes.len() as u64);
+        }
+        chunk
+    })
+}
+
+/// Mints and verifies share tokens. A token is the base64 encoded claims and their HMAC, so
+/// nothing is stored on the server except the download counters, which start over when the
+/// server restarts.
+pub struct ShareStore {
+    key: Vec<u8>,
+    /// The host names of the vhost the tokens are for, empty for the top level site.
+    hosts: Vec<String>,
+    downloads: Mutex<HashMap<String, Downloads>>,
+}
+
+impl ShareStore {
+    /// Tokens are signed with `secret`, or with a random key when it is not set, in which case
+    /// they stop working when the server restarts. The key of a vhost is derived from `secret`
+    /// and its `hosts`, so that its tokens grant nothing on the other sites.
+    pub fn new(secret: Option<&str>, hosts: &[String]) -> Self {
+        let hosts = hosts
+            .iter()
+            .map(|host| host.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        let key = match secret {
+            Some(secret) if hosts.is_empty() => secret.as_bytes().to_vec(),
+            Some(secret) => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key size works");
+                mac.update(b"vhost\0");
+                mac.update(hosts.join("\0").as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+            None => {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        Self {
+            key,
+            hosts,
+            downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the store signs tokens for the site with `hosts`.
+    pub fn is_for(&self, hosts: &[String]) -> bool {
+        self.hosts.len() == hosts.len()
+            && self
+                .hosts
+                .iter()
+                .zip(hosts)
+                .all(|(own, host)| own.eq_ignore_ascii_case(host))
+    }
+
+    fn mac(&self, parts: &[&str]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key size works");
+        for part in parts {
+            mac.update(part.as_bytes());
+            mac.update(b"\0");
+        }
+        mac
+    }
+
+    fn tag(&self, parts: &[&str]) -> String {
+        base64::URL_SAFE_NO_PAD.encode(self.mac(parts).finalize().into_bytes())
+    }
+
+    /// Whether `tag` is the tag of `parts`, compared in constant time.
+    fn has_tag(&self, parts: &[&str], tag: &str) -> bool {
+        base64::URL_SAFE_NO_PAD
+            .decode(tag)
+            .is_ok_and(|tag| self.mac(parts).verify_slice(&tag).is_ok())
+    }
+
+    /// Creates a token for `rel_path` and returns it with its expiry in seconds since the epoch.
+    pub fn mint(
+        &self,
+        rel_path: &str,
+        ttl: Duration,
+        max_downloads: Option<u64>,
+        password: Option<&str>,
+    ) -> Result<(String, u64)> {
+        let path = path_res::normalize(rel_path)
+            .map_err(|err| anyhow!("Invalid path [{}]: {:?}", rel_path, err))?;
+        let mut id = [0; 12];
+        OsRng.fill_bytes(&mut id);
+        let id = base64::URL_SAFE_NO_PAD.encode(id);
+        let exp = unix_now()
+            .checked_add(ttl.as_secs())
+            .filter(|_| ttl.as_secs() <= config::MAX_SHARE_EXPIRES_IN)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Expiry too far, at most {} seconds",
+                    config::MAX_SHARE_EXPIRES_IN
+                )
+            })?;
+        let claims = Claims {
+            pw: password.map(|password| self.tag(&["pw", &id, password])),
+            id,
+            path,
+            exp,
+            max: max_downloads,
+        };
+        let payload = base64::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let sig = self.tag(&["share", &payload]);
+        Ok((format!("{}.{}", payload, sig), exp))
+    }
+
+    /// Checks the signature and expiry of `token`. A token from the share cookie may carry a
+    /// proof that the password has been given, otherwise shares with a password are returned
+    /// locked.
+    pub fn verify(&self, token: &str) -> Result<ShareGrant, ShareError> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(payload), Some(sig)) = (parts.next(), parts.next()) else {
+            return Err(ShareError::Invalid);
+        };
+        if !self.has_tag(&["share", payload], sig) {
+            return Err(ShareError::Invalid);
+        }
+        let claims = base64::URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice::<Claims>(&payload).ok())
+            .ok_or(ShareError::Invalid)?;
+        if claims.exp <= unix_now() {
+            return Err(ShareError::Expired);
+        }
+        let unlocked = parts
+            .next()
+            .is_some_and(|proof| self.has_tag(&["unlocked", &claims.id], proof));
+        Ok(ShareGrant {
+            locked: claims.pw.is_some() && !unlocked,
+            id: claims.id,
+            path: claims.path,
+            exp: claims.exp,
+            max: claims.max,
+            pw: claims.pw,
+        })
+    }
+
+    /// Unlocks `grant` when `password` is the share password.
+    pub fn unlock(&self, grant: &mut ShareGrant, password: &str) -> bool {
+        if grant
+            .pw
+            .as_ref()
+            .is_some_and(|pw| self.has_tag(&["pw", &grant.id, password], pw))
+        {
+            grant.locked = false;
+        }
+        !grant.locked
+    }
+
+    /// Counts a download through `grant`, returns `false` when its limit is already reached.
+    pub fn count_download(&self, grant: &ShareGrant) -> bool {
+        let Some(max) = grant.max else {
+            return true;
+        };
+        let mut downloads = self.downloads.lock().unwrap();
+        let downloads = downloads.entry(grant.id.clone()).or_default();
+        if downloads.count >= max {
+            return false;
+        }
+        downloads.count += 1;
+        true
+    }
+
+    /// Reserves `requested` bytes of the file with `etag` and `size` for a download through
+    /// `grant`. They are part of the download last counted for the file as long as no more than
+    /// its size is served in all, as when resuming it or seeking in a video, otherwise the
+    /// request counts as a new download. Returns `None` when that is over the limit.
+    fn reserve_download(
+        &self,
+        grant: &ShareGrant,
+        etag: &str,
+        size: u64,
+        requested: u64,
+    ) -> Option<Reservation> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let downloads = downloads.entry(grant.id.clone()).or_default();
+        let served = match downloads.served.get(etag) {
+            Some(served)
+                if served
+                    .load(Ordering::Relaxed)
+                    .checked_add(requested)
+                    .is_some_and(|total| total <= size) =>
+            {
+                served.clone()
+            }
+            _ => {
+                if grant.max.is_some_and(|max| downloads.count >= max) {
+                    return None;
+                }
+                downloads.count += 1;
+                let served = Arc::new(AtomicU64::new(0));
+                downloads.served.insert(String::from(etag), served.clone());
+                served
+            }
+        };
+        served.fetch_add(requested, Ordering::Relaxed);
+        Some(Reservation {
+            served,
+            unsent: requested,
+        })
+    }
+
+    /// Whether the download limit of `grant` is reached, without counting anything.
+    pub fn is_exhausted(&self, grant: &ShareGrant) -> bool {
+        grant.max.is_some_and(|max| {
+            self.downloads
+                .lock()
+                .unwrap()
+                .get(&grant.id)
+                .is_some_and(|downloads| downloads.count >= max)
+        })
+    }
+
+    /// The cookie letting the reader of a shared epub open the rest of the book once reading it
+    /// has been counted as a download. It is scoped to the reader URLs of the book.
+    fn read_cookie(
+        &self,
+        grant: &ShareGrant,
+        path: &str,
+        b64_path: &str,
+        secure: bool,
+    ) -> Cookie<'static> {
+        let max_age = grant.exp.saturating_sub(unix_now());
+        Cookie::build(SHARE_READ_COOKIE, self.tag(&["read", &grant.id, path]))
+            .path(format!("/epub_cont/{}/", b64_path))
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(secure)
+            .max_age(time::Duration::seconds(max_age as i64))
+            .finish()
+    }
+
+    /// The cookie keeping the share while browsing the shared folder or reading the shared epub,
+    /// whose links do not carry the token. It also remembers that the password has been given.
+    pub fn cookie(&self, token: &str, grant: &ShareGrant, secure: bool) -> Cookie<'static> {
+        // drops a proof the query token may carry, the password is checked again
+        let mut value = token.splitn(3, '.').take(2).collect::<Vec<_>>().join(".");
+        if grant.pw.is_some() && !grant.locked {
+            value = format!("{}.{}", value, self.tag(&["unlocked", &grant.id]));
+        }
+        let max_age = grant.exp.saturating_sub(unix_now());
+        Cookie::build(SHARE_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(secure)
+            .max_age(time::Duration::seconds(max_age as i64))
+            .finish()
+    }
+}
+
+/// The share grant a request for `rel_path` relies on, `None` when there is none or when the
+/// requester has access of their own and is not limited by a share they also have.
+fn relied_grant<'a>(
+    req: &'a HttpRequest,
+    app_state: &AppState,
+    rel_path: &str,
+) -> Option<Ref<'a, ShareGrant>> {
+    let path = path_res::normalize(rel_path).ok()?;
+    let user = req.extensions().get::<Identity>().map(|id| id.user.clone());
+    let auth_enabled = req.app_data::<AuthInfo>().is_some();
+    if app_state
+        .acl
+        .allows(&path, user.as_deref(), Perm::Read, auth_enabled)
+    {
+        return None;
+    }
+    Ref::filter_map(req.extensions(), |ext| ext.get::<ShareGrant>()).ok()
+}
+
+fn limit_reached() -> HttpResponse {
+    HttpResponse::Gone().body("Download limit of the share link reached")
+}
+
+/// Applies the download limit of the share link the request for `rel_path` goes through, if
+/// any. A `counted` request is a download, others are only refused once the limit is reached.
+/// Returns the grant the request relies on, see [`relied_grant`].
+fn limit<'a>(
+    req: &'a HttpRequest,
+    app_state: &AppState,
+    rel_path: &str,
+    counted: bool,
+) -> Result<Option<Ref<'a, ShareGrant>>, HttpResponse> {
+    let Some(grant) = relied_grant(req, app_state, rel_path) else {
+        return Ok(None);
+    };
+    let exhausted = match counted {
+        true => !app_state.shares.count_download(&grant),
+        false => app_state.shares.is_exhausted(&grant),
+    };
+    match exhausted {
+        true => Err(limit_reached()),
+        false => Ok(Some(grant)),
+    }
+}
+
+/// A GET of a shared file, for the bytes it asks for.
+pub(crate) struct FileDownload<'a> {
+    pub etag: &'a str,
+    pub size: u64,
+    pub requested: u64,
+}
+
+/// Applies the download limit of the share link a file is downloaded through. A `download`
+/// reserves its bytes, see [`ShareStore::reserve_download`], the returned reservation must be
+/// tracked while the response is sent. Other requests are only refused once the limit is
+/// reached.
+pub(crate) fn limit_download(
+    req: &HttpRequest,
+    app_state: &AppState,
+    rel_path: &str,
+    download: Option<FileDownload>,
+) -> Result<Option<Reservation>, HttpResponse> {
+    let Some(download) = download else {
+        return limit(req, app_state, rel_path, false).map(|_| None);
+    };
+    let Some(grant) = relied_grant(req, app_state, rel_path).filter(|grant| grant.max.is_some())
+    else {
+        return Ok(None);
+    };
+    app_state
+        .shares
+        .reserve_download(&grant, download.etag, download.size, download.requested)
+        .map(Some)
+        .ok_or_else(limit_reached)
+}
+
+/// Applies the download limit of the share link an epub is read through. Reading a book counts
+/// as one download when the first of its contents is served, the returned cookie then lets the
+/// same browser read the rest of it without counting.
+pub(crate) fn limit_epub_read(
+    req: &HttpRequest,
+    app_state: &AppState,
+    rel_path: &str,
+    b64_path: &str,
+) -> Result<Option<Cookie<'static>>, HttpResponse> {
+    let Ok(path) = path_res::normalize(rel_path) else {
+        return Ok(None);
+    };
+    let read_cookie = req.cookie(SHARE_READ_COOKIE);
+    // a book already counted can be read on, whether or not the limit has been reached since
+    let reading = req.extensions().get::<ShareGrant>().is_none_or(|grant| {
+        read_cookie.as_ref().is_some_and(|cookie| {
+            app_state
+                .shares
+                .has_tag(&["read", &grant.id, &path], cookie.value())
+        })
+    });
+    if reading {
+        return Ok(None);
+    }
+    let secure = tls::is_https(req);
+    let Some(grant) = limit(req, app_state, &path, true)? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        app_state
+            .shares
+            .read_cookie(&grant, &path, b64_path, secure),
+    ))
+}
+
+/// The share token of a request, from the query string or else from the cookie set on the first
+/// visit. The flag tells whether it came from the query string.
+pub fn request_token(req: &ServiceRequest) -> Option<(String, bool)> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+    if let Some(token) = query.and_then(|mut query| query.remove(SHARE_PARAM)) {
+        return Some((token, true));
+    }
+    req.cookie(SHARE_COOKIE)
+        .map(|cookie| (String::from(cookie.value()), false))
+}
+
+/// The URL of a shared path, with its segments percent-encoded.
+pub fn share_url(rel_path: &str, token: &str) -> String {
+    let path = rel_path
+        .split('/')
+        .filter(|seg| !seg.is_empty())
+        .map(|seg| urlencoding::encode(seg).into_owned())
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("/{}?{}={}", path, SHARE_PARAM, token)
+}
+
+/// Prints a share link for the `share` subcommand, for the vhost serving `host` when given.
+pub fn run_share_command(
+    config: &config::Config,
+    host: Option<&str>,
+    path: &str,
+    expires_in: u64,
+    max_downloads: Option<u64>,
+    password: Option<&str>,
+    base_url: &str,
+) -> Result<()> {
+    let secret = config
+        .share_secret
+        .as_deref()
+        .ok_or(anyhow!("No `share_secret` in the config file"))?;
+    let path =
+        path_res::normalize(path).map_err(|err| anyhow!("Invalid path [{}]: {:?}", path, err))?;
+    let hosts = match host {
+        Some(host) => config
+            .vhosts
+            .iter()
+            .find(|vhost| vhost.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|vhost| vhost.hosts.as_slice())
+            .ok_or_else(|| anyhow!("No vhost serves host [{}]", host))?,
+        None => &[],
+    };
+    let (token, _) = ShareStore::new(Some(secret), hosts).mint(
+        &path,
+        Duration::from_secs(expires_in),
+        max_downloads,
+        password,
+    )?;
+    println!(
+        "{}{}",
+        base_url.trim_end_matches('/'),
+        share_url(&path, &token)
+    );
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ShareForm {
+    path: String,
+    /// Seconds the link stays valid.
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ShareLink {
+    url: String,
+    token: String,
+    expires: String,
+}
+
+/// Lets a signed in user create a share link for a path they can read or list.
+pub(crate) async fn create_share(
+    req: HttpRequest,
+    form: web::Form<ShareForm>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let Some(user) = req.extensions().get::<Identity>().map(|id| id.user.clone()) else {
+        return HttpResponse::Unauthorized().body("Only signed in users can share");
+    };
+    if let Err(resp) = session::check_same_origin(&req) {
+        return resp;
+    }
+    let path = match path_res::normalize(&form.path) {
+        Ok(path) => path,
+        Err(err) => return err.to_response(),
+    };
+    // checked against the rules alone, shares cannot be passed on
+    if ![Perm::Read, Perm::List]
+        .into_iter()
+        .any(|perm| app_state.acl.allows(&path, Some(&user), perm, true))
+    {
+        return HttpResponse::Forbidden().body("Access denied");
+    }
+    let ttl = Duration::from_secs(form.expires_in.unwrap_or(config::DEFAULT_SHARE_EXPIRES_IN));
+    let password = form
+        .password
+        .as_deref()
+        .filter(|password| !password.is_empty());
+    let (token, exp) = match app_state
+        .shares
+        .mint(&path, ttl, form.max_downloads, password)
+    {
+        Ok(res) => res,
+        Err(err) => return HttpResponse::BadRequest().body(format!("{}", err)),
+    };
+    let expires = DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(exp))
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    HttpResponse::Ok().json(ShareLink {
+        url: share_url(&path, &token),
+        token,
+        expires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_config;
+    use crate::auth::{AuthInfo, authenticate};
+    use actix_http::StatusCode;
+    use actix_web::http::Method;
+    use actix_web::http::header::{AUTHORIZATION, RANGE, WWW_AUTHENTICATE};
+    use actix_web::test::{
+        TestRequest, call_and_read_body_json, call_service, init_service, read_body,
+    };
+    use actix_web::{App, middleware::from_fn};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_share_token() {
+        let store = ShareStore::new(Some("secret"), &[]);
+        let (token, _) = store
+            .mint("res_dir//v2.epub", Duration::from_secs(60), Some(1), None)
+            .unwrap();
+        let grant = store.verify(&token).unwrap();
+        assert_eq!(grant.path, "/res_dir/v2.epub");
+        assert!(grant.covers("/res_dir/v2.epub"));
+        assert!(!grant.covers("/res_dir/v2.epub2"));
+        assert!(store.count_download(&grant));
+        assert!(store.is_exhausted(&grant));
+        assert!(!store.count_download(&grant));
+
+        // signed with another secret
+        let other = ShareStore::new(Some("other"), &[]);
+        assert!(matches!(other.verify(&token), Err(ShareError::Invalid)));
+        // tampered claims
+        let (_, sig) = token.split_once('.').unwrap();
+        let (token2, _) = store
+            .mint("/", Duration::from_secs(60), None, None)
+            .unwrap();
+        let (payload2, _) = token2.split_once('.').unwrap();
+        let forged = format!("{}.{}", payload2, sig);
+        assert!(matches!(store.verify(&forged), Err(ShareError::Invalid)));
+
+        let (token, _) = store.mint("/res_dir", Duration::ZERO, None, None).unwrap();
+        assert!(matches!(store.verify(&token), Err(ShareError::Expired)));
+        assert!(
+            store
+                .mint("/res_dir", Duration::from_secs(u64::MAX), None, None)
+                .is_err()
+        );
+
+        let (token, _) = store
+            .mint("/res_dir", Duration::from_secs(60), None, Some("pw"))
+            .unwrap();
+        let mut grant = store.verify(&token).unwrap();
+        assert!(grant.is_locked());
+        assert!(!store.unlock(&mut grant, "PW"));
+        assert!(store.unlock(&mut grant, "pw"));
+        assert!(grant.covers("/res_dir/images/a.png"));
+        // the cookie set after the password was given unlocks the share
+        let cookie = store.cookie(&token, &grant, false);
+        assert!(!store.verify(cookie.value()).unwrap().is_locked());
+        let forged = format!("{}.{}", token, sig);
+        assert!(store.verify(&forged).unwrap().is_locked());
+    }
+
+    #[test]
+    fn test_share_url() {
+        assert_eq!(share_url("/a b/c#.epub", "t"), "/a%20b/c%23.epub?share=t");
+    }
+
+    #[actix_web::test]
+    async fn test_share_links() {
+        let users = HashMap::from([(String::from("alice"), bcrypt::hash("alicepass", 4).unwrap())]);
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.shares = Arc::new(ShareStore::new(Some("secret"), &[]));
+        let app_data = web::Data::new(app_state);
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(app_data.clone())
+                .app_data(AuthInfo::new(users))
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+        let basic = |cred: &str| {
+            (
+                AUTHORIZATION,
+                format!("Basic {}", base64::STANDARD.encode(cred)),
+            )
+        };
+
+        // minted through the endpoint by a signed in user
+        let req = TestRequest::post()
+            .uri("/share")
+            .set_form([("path", "/res_dir"), ("max_downloads", "1")])
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = TestRequest::post()
+            .uri("/share")
+            .insert_header(basic("alice:alicepass"))
+            .set_form([("path", "/res_dir"), ("max_downloads", "1")])
+            .to_request();
+        let link: serde_json::Value = call_and_read_body_json(&app, req).await;
+        let url = link["url"].as_str().unwrap();
+        let req = TestRequest::post()
+            .uri("/share")
+            .insert_header(basic("alice:alicepass"))
+            .insert_header(("Origin", "https://evil.example"))
+            .set_form([("path", "/res_dir")])
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(url.starts_with("/res_dir?share="));
+        let req = TestRequest::post()
+            .uri("/share")
+            .insert_header(basic("alice:alicepass"))
+            .set_form([("path", "/res_dir"), ("expires_in", "18446744073709551615")])
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::default().uri(url).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(cookie.name(), SHARE_COOKIE);
+
+        // the cookie carries the share to the links of the listing and the epub reader
+        for (uri, status) in [
+            ("/res_dir/images/", StatusCode::OK),
+            ("/epub_toc/res_dir/v2.epub", StatusCode::OK),
+            ("/src/lib.rs", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = TestRequest::default()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+        // ranges within the size of the file are part of the download they start, more of it is
+        // a new download
+        for (range, status) in [
+            (Some("bytes=10-19"), StatusCode::PARTIAL_CONTENT),
+            (Some("bytes=20-8000"), StatusCode::PARTIAL_CONTENT),
+            (Some("bytes=1-"), StatusCode::GONE),
+            (None, StatusCode::GONE),
+            (Some("bytes=0-"), StatusCode::GONE),
+            (Some("bytes=99999-"), StatusCode::GONE),
+        ] {
+            let mut req = TestRequest::default()
+                .uri("/res_dir/dummy.pdf")
+                .cookie(cookie.clone());
+            if let Some(range) = range {
+                req = req.insert_header((RANGE, range));
+            }
+            let resp = call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "{:?}", range);
+            read_body(resp).await;
+        }
+        // ranges past the start cannot fetch the file again and again
+        let (token, _) = app_data
+            .shares
+            .mint("/res_dir/dummy.pdf", Duration::from_secs(60), Some(2), None)
+            .unwrap();
+        for status in [
+            StatusCode::PARTIAL_CONTENT,
+            StatusCode::PARTIAL_CONTENT,
+            StatusCode::GONE,
+        ] {
+            let req = TestRequest::default()
+                .uri(&share_url("/res_dir/dummy.pdf", &token))
+                .insert_header((RANGE, "bytes=1-"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+            read_body(resp).await;
+        }
+        // bytes not sent are given back, an interrupted download can be resumed at the limit
+        let (token, _) = app_data
+            .shares
+            .mint("/res_dir/dummy.pdf", Duration::from_secs(60), Some(1), None)
+            .unwrap();
+        let url = share_url("/res_dir/dummy.pdf", &token);
+        let req = TestRequest::default().uri(&url).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        drop(resp);
+        for (range, status) in [
+            ("bytes=4000-", StatusCode::PARTIAL_CONTENT),
+            ("bytes=0-3999", StatusCode::PARTIAL_CONTENT),
+            ("bytes=0-0", StatusCode::GONE),
+        ] {
+            let req = TestRequest::default()
+                .uri(&url)
+                .insert_header((RANGE, range))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", range);
+            read_body(resp).await;
+        }
+
+        // reading a shared epub counts once, the reader cookie covers the rest of the book
+        let (token, _) = app_data
+            .shares
+            .mint("/res_dir/v2.epub", Duration::from_secs(60), Some(1), None)
+            .unwrap();
+        let req = TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&share_url("/res_dir/v2.epub", &token))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let b64_path = base64::URL_SAFE_NO_PAD.encode("res_dir/v2.epub");
+        let chapter = |name: &str| format!("/epub_cont/{}/OEBPS/{}", b64_path, name);
+        let req = TestRequest::default()
+            .uri(&chapter("valentinhauy11.html"))
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let read_cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(read_cookie.name(), SHARE_READ_COOKIE);
+        assert_eq!(
+            read_cookie.path(),
+            Some(format!("/epub_cont/{}/", b64_path).as_str())
+        );
+        let req = TestRequest::default()
+            .uri(&chapter("base.css"))
+            .cookie(cookie.clone())
+            .cookie(read_cookie)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for uri in [chapter("base.css"), String::from("/res_dir/v2.epub")] {
+            let req = TestRequest::default()
+                .uri(&uri)
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::GONE, "{}", uri);
+        }
+
+        // password protected, the password is given as the basic auth password
+        let (token, _) = app_data
+            .shares
+            .mint(
+                "/res_dir/v2.epub",
+                Duration::from_secs(60),
+                None,
+                Some("pw"),
+            )
+            .unwrap();
+        let url = share_url("/res_dir/v2.epub", &token);
+        let req = TestRequest::default().uri(&url).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(WWW_AUTHENTICATE));
+        let req = TestRequest::default()
+            .uri(&url)
+            .insert_header(basic(":pw"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (token, _) = app_data
+            .shares
+            .mint("/res_dir", Duration::ZERO, None, None)
+            .unwrap();
+        let req = TestRequest::default()
+            .uri(&share_url("/res_dir", &token))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+    }
+
+    #[actix_web::test]
+    async fn test_share_password_lockout() {
+        // without users, share passwords are still throttled
+        let mut app_state = AppState::new(PathBuf::from("."));
+        app_state.shares = Arc::new(ShareStore::new(Some("secret"), &[]));
+        app_state.share_throttle = crate::throttle::Throttle::new(crate::throttle::AuthLimits {
+            max_failures: 2,
+            ..Default::default()
+        });
+        let app_data = web::Data::new(app_state);
+        let app = init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(app_data.clone())
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+        let (token, _) = app_data
+            .shares
+            .mint("/res_dir", Duration::from_secs(60), None, Some("pw"))
+            .unwrap();
+        let (other, _) = app_data
+            .shares
+            .mint("/res_dir", Duration::from_secs(60), None, Some("pw"))
+            .unwrap();
+        for (token, password, status) in [
+            (&token, "wrong1", StatusCode::UNAUTHORIZED),
+            (&token, "wrong2", StatusCode::UNAUTHORIZED),
+            (&token, "pw", StatusCode::TOO_MANY_REQUESTS),
+            // the link is locked out, not the other links of a client without an address
+            (&other, "pw", StatusCode::OK),
+        ] {
+            let req = TestRequest::default()
+                .uri(&share_url("/res_dir", token))
+                .insert_header((
+                    AUTHORIZATION,
+                    format!(
+                        "Basic {}",
+                        base64::STANDARD.encode(format!(":{}", password))
+                    ),
+                ))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", password);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::os::fd::RawFd;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+
+/// The first descriptor passed by socket activation, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether an environment variable naming a process, like `LISTEN_PID`, names this one.
+fn for_this_process(pid: Option<&str>) -> bool {
+    pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id())
+}
+
+/// The listening sockets passed with `LISTEN_PID` and `LISTEN_FDS`.
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>) -> Range<RawFd> {
+    if !for_this_process(listen_pid) {
+        return LISTEN_FDS_START..LISTEN_FDS_START;
+    }
+    let count = listen_fds.and_then(|fds| fds.parse::<RawFd>().ok());
+    let end = count.and_then(|count| LISTEN_FDS_START.checked_add(count));
+    LISTEN_FDS_START..end.unwrap_or(LISTEN_FDS_START)
+}
+
+/// The listening sockets systemd passed to this process by socket activation, if any.
+pub(crate) fn listen_fds() -> Range<RawFd> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    parse_listen_fds(listen_pid.as_deref(), listen_fds.as_deref())
+}
+
+fn send(notify_socket: &OsStr, state: &str) -> std::io::Result<()> {
+    let path = notify_socket.as_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(std::io::Error::other("abstract sockets are not supported")),
+        None => SocketAddr::from_pathname(notify_socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Tells systemd about the state of the service, such as `READY=1`. Does nothing unless the
+/// service has `Type=notify`, which sets `NOTIFY_SOCKET`.
+pub(crate) fn notify(state: &str) {
+    if let Some(notify_socket) = std::env::var_os("NOTIFY_SOCKET")
+        && let Err(err) = send(&notify_socket, state)
+    {
+        eprintln!("Notifying systemd of [{}] failed: {}", state, err);
+    }
+}
+
+/// Sends `WATCHDOG=1` at half the interval of `WatchdogSec=`, when it is set for the service.
+pub(crate) fn spawn_watchdog() {
+    let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+    if watchdog_pid.is_some() && !for_this_process(watchdog_pid.as_deref()) {
+        return;
+    }
+    let Some(usec) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+    else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Resolves on SIGTERM or SIGINT, after telling systemd the service is stopping. The server
+/// then stops accepting connections and gives the requests being served `timeout` seconds to
+/// finish.
+pub(crate) async fn shutdown_signal(timeout: u64) {
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        eprintln!("Listening for SIGTERM and SIGINT failed");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    notify("STOPPING=1");
+    eprintln!(
+        "Shutting down, waiting up to {} seconds for requests to finish",
+        timeout
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_systemd() {
+        let pid = std::process::id().to_string();
+        assert_eq!(parse_listen_fds(Some(&pid), Some("2")), 3..5);
+        // the sockets were meant for another process
+        assert!(parse_listen_fds(Some("1"), Some("2")).is_empty());
+        assert!(parse_listen_fds(None, None).is_empty());
+        assert!(parse_listen_fds(Some(&pid), Some("2147483647")).is_empty());
+        assert!(parse_listen_fds(Some(&pid), Some("-1")).is_empty());
+
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
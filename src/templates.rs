@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use minijinja::{AutoEscape, Environment, Error, Output, State, Value};
+use serde::Serialize;
+use std::path::Path;
+
+/// Template names with their embedded default sources. A file with the same name in the
+/// configured `templates_dir` replaces the default one.
+const DEFAULT_TEMPLATES: [(&str, &str); 2] = [
+    ("dir_list.html", include_str!("templates/dir_list.html")),
+    ("login.html", include_str!("templates/login.html")),
+];
+
+/// Escapes the characters which are significant in HTML text and attribute values.
+pub fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Same as the default minijinja formatter except that `/` is left alone, so URLs stay readable.
+fn html_formatter(out: &mut Output, state: &State, value: &Value) -> Result<(), Error> {
+    if state.auto_escape() == AutoEscape::Html
+        && !value.is_safe()
+        && let Some(s) = value.as_str()
+    {
+        return out.write_str(&html_escape(s)).map_err(Error::from);
+    }
+    minijinja::escape_formatter(out, state, value)
+}
+
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::new(None).expect("embedded templates are valid")
+    }
+}
+
+impl Templates {
+    pub fn new(templates_dir: Option<&Path>) -> Result<Self> {
+        let mut env = Environment::new();
+        env.set_formatter(html_formatter);
+        for (name, source) in DEFAULT_TEMPLATES {
+            let path = templates_dir.map(|dir| dir.join(name));
+            match path {
+                Some(path) if path.is_file() => {
+                    let source = std::fs::read_to_string(&path)
+                        .with_context(|| format!("Reading template [{:?}] failed", &path))?;
+                    env.add_template_owned(name, source)
+                        .with_context(|| format!("Parsing template [{:?}] failed", &path))?;
+                }
+                _ => env.add_template(name, source)?,
+            }
+        }
+        Ok(Self { env })
+    }
+
+    pub fn render<S: Serialize>(&self, name: &str, ctx: S) -> Result<String, Error> {
+        self.env
+            .get_template(name)?
+            .render(Value::from_serialize(ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_html_escape() {
+        assert_eq!(
+            html_escape(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(html_escape("/path/文件.txt"), "/path/文件.txt");
+    }
+
+    #[test]
+    fn test_templates_override() {
+        let dir = TempDir::new("tmpl");
+        std::fs::write(dir.join("dir_list.html"), "<p>{{ path }}</p>").unwrap();
+        let templates = Templates::new(Some(&dir)).unwrap();
+        #[derive(Serialize)]
+        struct Ctx {
+            path: &'static str,
+        }
+        let out = templates
+            .render("dir_list.html", Ctx { path: "/a<b>/" })
+            .unwrap();
+        assert_eq!(out, "<p>/a&lt;b&gt;/</p>");
+
+        std::fs::write(dir.join("dir_list.html"), "{% if %}").unwrap();
+        assert!(Templates::new(Some(&dir)).is_err());
+    }
+}
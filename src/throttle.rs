@@ -0,0 +1,202 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits applied to password verification.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuthLimits {
+    /// Failures from one client or for one user before it is locked out, `0` disables lockouts.
+    pub max_failures: u32,
+    /// The first lockout, doubled with every further failure.
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+    /// Max number of password hashes verified at the same time.
+    pub verify_concurrency: usize,
+}
+
+impl Default for AuthLimits {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(3600),
+            verify_concurrency: 2,
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum Key {
+    Ip(IpAddr),
+    User(String),
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Writes a line for log watchers such as fail2ban, e.g.
+/// `2026-01-02T03:04:05Z auth_failure ip=192.0.2.1 user="alice"`.
+pub fn log_event(event: &str, ip: Option<IpAddr>, user: &str, extra: &str) {
+    let ip = ip.map_or(String::from("-"), |ip| ip.to_string());
+    eprintln!(
+        "{} {} ip={} user={:?}{}",
+        DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Secs, true),
+        event,
+        ip,
+        user,
+        extra
+    );
+}
+
+/// Counts authentication failures per client IP and per user, and locks them out with an
+/// exponentially growing delay once they fail too often.
+pub struct Throttle {
+    limits: AuthLimits,
+    failures: Arc<Mutex<HashMap<Key, Failures>>>,
+    permits: Semaphore,
+}
+
+impl Throttle {
+    pub fn new(limits: AuthLimits) -> Self {
+        Self {
+            limits,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            permits: Semaphore::new(limits.verify_concurrency.max(1)),
+        }
+    }
+
+    /// A throttle with other limits sharing the failure counts and lockouts of this one, so that
+    /// reloading the config does not lift them.
+    pub fn with_limits(&self, limits: AuthLimits) -> Self {
+        Self {
+            limits,
+            failures: self.failures.clone(),
+            permits: Semaphore::new(limits.verify_concurrency.max(1)),
+        }
+    }
+
+    pub fn limits(&self) -> AuthLimits {
+        self.limits
+    }
+
+    /// Clients without an address, such as those connecting through a Unix socket, are only
+    /// counted per user, as they may be anyone behind a reverse proxy.
+    fn keys(ip: Option<IpAddr>, user: Option<&str>) -> impl Iterator<Item = Key> {
+        ip.map(Key::Ip)
+            .into_iter()
+            .chain(user.map(|user| Key::User(String::from(user))))
+    }
+
+    /// The remaining lockout of the client or the user, if any of them is locked out.
+    pub fn locked(&self, ip: Option<IpAddr>, user: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        Self::keys(ip, user)
+            .filter_map(|key| failures.get(&key)?.locked_until)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    /// Counts a failure for the client and, when `user` exists, for the user. Returns the
+    /// lockout started by this failure.
+    pub fn record_failure(&self, ip: Option<IpAddr>, user: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        // failures long ago are forgotten, which also keeps the map small
+        failures.retain(|_, entry| now.duration_since(entry.last) < self.limits.max_lockout);
+        let mut lockout = None;
+        for key in Self::keys(ip, user) {
+            let entry = failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            entry.last = now;
+            if self.limits.max_failures > 0 && entry.count >= self.limits.max_failures {
+                let doublings = (entry.count - self.limits.max_failures).min(16);
+                let duration = self
+                    .limits
+                    .lockout
+                    .saturating_mul(1 << doublings)
+                    .min(self.limits.max_lockout);
+                entry.locked_until = Some(now + duration);
+                lockout = lockout.max(Some(duration));
+            }
+        }
+        lockout
+    }
+
+    pub fn record_success(&self, ip: Option<IpAddr>, user: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in Self::keys(ip, Some(user)) {
+            failures.remove(&key);
+        }
+    }
+
+    /// Waits for a free slot for verifying a password hash.
+    pub async fn permit(&self) -> SemaphorePermit<'_> {
+        self.permits.acquire().await.expect("never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(AuthLimits {
+            max_failures: 3,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(30),
+            verify_concurrency: 1,
+        });
+        let ip1: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip2: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(throttle.record_failure(Some(ip1), Some("alice")), None);
+        assert_eq!(throttle.record_failure(Some(ip1), None), None);
+        assert_eq!(throttle.locked(Some(ip1), Some("alice")), None);
+        // the 3rd failure of the client locks it out, alice has only failed once
+        assert_eq!(
+            throttle.record_failure(Some(ip1), Some("bob")),
+            Some(Duration::from_secs(10))
+        );
+        assert!(throttle.locked(Some(ip1), None).is_some());
+        assert!(throttle.locked(Some(ip2), Some("alice")).is_none());
+        // lockouts double up to the max
+        assert_eq!(
+            throttle.record_failure(Some(ip1), None),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            throttle.record_failure(Some(ip1), None),
+            Some(Duration::from_secs(30))
+        );
+        // the user is locked out from any client
+        throttle.record_failure(Some(ip2), Some("alice"));
+        throttle.record_failure(Some(ip2), Some("alice"));
+        assert!(throttle.locked(Some(ip2), Some("alice")).is_some());
+        assert!(throttle.locked(None, Some("alice")).is_some());
+
+        // clients without an address are only counted per user
+        for _ in 0..3 {
+            throttle.record_failure(None, Some("bob"));
+        }
+        assert!(throttle.locked(None, Some("bob")).is_some());
+        assert!(throttle.locked(None, None).is_none());
+        assert!(throttle.locked(None, Some("carol")).is_none());
+
+        throttle.record_success(Some(ip2), "alice");
+        assert!(throttle.locked(Some(ip2), Some("alice")).is_none());
+        assert!(throttle.locked(Some(ip1), Some("alice")).is_some());
+    }
+}
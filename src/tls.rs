@@ -0,0 +1,813 @@
+use crate::config::{CertUserField, ClientAuth, Config, GenCertArgs, TlsVersion, VhostEntry};
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HOST, HeaderValue, LOCATION, STRICT_TRANSPORT_SECURITY};
+use actix_web::http::{Method, Uri};
+use actix_web::middleware::Next;
+use actix_web::rt::net::TcpStream;
+use actix_web::{HttpRequest, HttpResponse, web};
+use anyhow::{Result, anyhow};
+use notify::{RecursiveMode, Watcher};
+use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, Issuer, KeyPair};
+use rustls::crypto::CryptoProvider;
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+    WebPkiClientVerifier,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::HashSet;
+use std::io::{BufReader, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use x509_parser::extensions::GeneralName;
+
+/// How long to wait for more changes to watched files before reloading them.
+pub(crate) const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// The user a verified client certificate maps to, stored in the connection data.
+pub struct ClientCert {
+    pub user: String,
+}
+
+/// Marks TLS connections. The app of the listeners offering a single ALPN protocol is not told it
+/// is served over HTTPS, so [`is_https`] looks for this instead.
+pub struct TlsConnection;
+
+/// Whether the request came over HTTPS, to this server or to a proxy in front of it.
+pub fn is_https(req: &HttpRequest) -> bool {
+    req.conn_data::<TlsConnection>().is_some() || req.connection_info().scheme() == "https"
+}
+
+/// Marks connections whose verified client certificate lacks the field naming the user, whose
+/// requests are refused rather than served as anonymous.
+pub struct UnknownCertUser;
+
+/// A certificate and key pair read from files, with the host names it is used for.
+#[derive(Debug)]
+struct CertSource {
+    /// Empty for the default pair.
+    hosts: Vec<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+/// Picks the certificate of a vhost by the SNI server name, or the default one. The pairs are
+/// read again from disk by [`CertResolver::reload`], a pair failing to load or not matching
+/// keeps being served as it was.
+#[derive(Debug)]
+pub struct CertResolver {
+    provider: Arc<CryptoProvider>,
+    /// The sources with their loaded pairs, the default one first.
+    pairs: RwLock<Vec<(CertSource, Arc<CertifiedKey>)>>,
+    /// Counts the calls of [`CertResolver::replace`], changed with `pairs` held.
+    generation: AtomicU64,
+    /// Set by [`watch_certs`], with the directories it watches.
+    watcher: Mutex<Option<(notify::RecommendedWatcher, HashSet<PathBuf>)>>,
+}
+
+/// The default pair and the pairs of the vhosts having one.
+fn cert_sources(cert_path: &Path, key_path: &Path, vhosts: &[VhostEntry]) -> Vec<CertSource> {
+    let mut sources = vec![CertSource {
+        hosts: Vec::new(),
+        cert_path: cert_path.to_path_buf(),
+        key_path: key_path.to_path_buf(),
+    }];
+    for vhost in vhosts {
+        if let (Some(cert_path), Some(key_path)) = (&vhost.cert_path, &vhost.key_path) {
+            sources.push(CertSource {
+                hosts: vhost
+                    .hosts
+                    .iter()
+                    .map(|host| host.to_ascii_lowercase())
+                    .collect(),
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            });
+        }
+    }
+    sources
+}
+
+impl CertResolver {
+    /// Loads the default pair and the pairs of the vhosts having one, failing when any of them
+    /// can not be loaded.
+    pub fn new(cert_path: &Path, key_path: &Path, vhosts: &[VhostEntry]) -> Result<Self> {
+        let provider = ServerConfig::builder().crypto_provider().clone();
+        let pairs = Self::load(&provider, cert_sources(cert_path, key_path, vhosts))?;
+        Ok(Self {
+            provider,
+            pairs: RwLock::new(pairs),
+            generation: AtomicU64::new(0),
+            watcher: Mutex::new(None),
+        })
+    }
+
+    fn load(
+        provider: &CryptoProvider,
+        sources: Vec<CertSource>,
+    ) -> Result<Vec<(CertSource, Arc<CertifiedKey>)>> {
+        sources
+            .into_iter()
+            .map(|source| {
+                let key = load_certified_key(&source.cert_path, &source.key_path, provider)?;
+                Ok((source, Arc::new(key)))
+            })
+            .collect()
+    }
+
+    /// Replaces all pairs by those of a reloaded config, unless any of them can not be loaded.
+    pub fn replace(&self, cert_path: &Path, key_path: &Path, vhosts: &[VhostEntry]) -> Result<()> {
+        let pairs = Self::load(&self.provider, cert_sources(cert_path, key_path, vhosts))?;
+        {
+            let mut current = self.pairs.write().unwrap();
+            *current = pairs;
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        self.watch_dirs()
+    }
+
+    /// Reads all pairs again. Returns the number of pairs that failed and were kept as before,
+    /// and the number of pairs.
+    pub fn reload(&self) -> (usize, usize) {
+        self.swap_in(self.load_again())
+    }
+
+    /// Runs [`CertResolver::reload`] on the blocking thread pool, as it reads and parses files.
+    pub async fn reload_blocking(self: Arc<Self>) -> (usize, usize) {
+        web::block(move || self.reload()).await.unwrap_or_default()
+    }
+
+    /// Loads the pairs again from their files, with the generation they are loaded for. No lock
+    /// is held meanwhile, so that handshakes are not held up by the disk.
+    fn load_again(&self) -> (u64, Vec<Option<CertifiedKey>>) {
+        let (generation, paths) = {
+            let pairs = self.pairs.read().unwrap();
+            let paths = pairs
+                .iter()
+                .map(|(source, _)| (source.cert_path.clone(), source.key_path.clone()))
+                .collect::<Vec<_>>();
+            (self.generation.load(Ordering::Relaxed), paths)
+        };
+        let keys = paths
+            .iter()
+            .map(|(cert_path, key_path)| {
+                load_certified_key(cert_path, key_path, &self.provider)
+                    .inspect_err(|err| eprintln!("Keeping the previous certificate: {}", err))
+                    .ok()
+            })
+            .collect();
+        (generation, keys)
+    }
+
+    /// Swaps in the pairs of [`CertResolver::load_again`] which loaded, unless a `replace` came
+    /// in between, whose pairs are newer. Returns the failed and total numbers of pairs.
+    fn swap_in(&self, (generation, keys): (u64, Vec<Option<CertifiedKey>>)) -> (usize, usize) {
+        let failed = keys.iter().filter(|key| key.is_none()).count();
+        let mut pairs = self.pairs.write().unwrap();
+        if self.generation.load(Ordering::Relaxed) == generation {
+            for ((_, key), new_key) in pairs.iter_mut().zip(keys) {
+                if let Some(new_key) = new_key {
+                    *key = Arc::new(new_key);
+                }
+            }
+        }
+        (failed, pairs.len())
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let pairs = self.pairs.read().unwrap();
+        let index = server_name
+            .map(|name| name.to_ascii_lowercase())
+            .and_then(|name| {
+                pairs
+                    .iter()
+                    .position(|(source, _)| source.hosts.contains(&name))
+            })
+            .unwrap_or(0);
+        pairs[index].1.clone()
+    }
+
+    /// The absolute paths of the files the pairs are read from.
+    fn paths(&self) -> HashSet<PathBuf> {
+        self.pairs
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|(source, _)| [&source.cert_path, &source.key_path])
+            .map(|path| std::path::absolute(path).unwrap_or_else(|_| path.clone()))
+            .collect()
+    }
+
+    /// Watches the directories of the pairs not watched yet, once [`watch_certs`] started.
+    fn watch_dirs(&self) -> Result<()> {
+        let mut watcher = self.watcher.lock().unwrap();
+        let Some((watcher, dirs)) = watcher.as_mut() else {
+            return Ok(());
+        };
+        // the directories are watched, as renewals often replace the files
+        for path in self.paths() {
+            if let Some(dir) = path.parent()
+                && dirs.insert(dir.to_path_buf())
+            {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.lookup(client_hello.server_name()))
+    }
+}
+
+/// Reloads the certificates when their files change, which usually happens in bursts while a
+/// certificate is renewed, so reloading waits for things to settle. SIGHUP reloads them along
+/// with the config, see [`crate::reload`].
+pub fn watch_certs(resolver: Arc<CertResolver>) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+        {
+            let _ = tx.send(event.paths);
+        }
+    })?;
+    *resolver.watcher.lock().unwrap() = Some((watcher, HashSet::new()));
+    resolver.watch_dirs()?;
+
+    tokio::spawn(async move {
+        while let Some(paths) = rx.recv().await {
+            let watched = resolver.paths();
+            if !paths.iter().any(|path| watched.contains(path)) {
+                continue;
+            }
+            tokio::time::sleep(SETTLE_DELAY).await;
+            while rx.try_recv().is_ok() {}
+            let (failed, total) = resolver.clone().reload_blocking().await;
+            eprintln!(
+                "Reloaded certificates, {} of {} kept as before",
+                failed, total
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Checks that the pair can be loaded and that the key belongs to the certificate.
+pub fn check_pair(cert_path: &Path, key_path: &Path) -> Result<()> {
+    let provider = ServerConfig::builder().crypto_provider().clone();
+    load_certified_key(cert_path, key_path, &provider).map(|_| ())
+}
+
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| anyhow!("Reading [{}] failed: {}", path.display(), err))
+    };
+    let cert_file = &mut open(cert_path)?;
+    let key_file = &mut open(key_path)?;
+
+    let cert_chain = rustls_pemfile::certs(cert_file).collect::<Result<Vec<_>, _>>()?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificate in [{}]", cert_path.display()));
+    }
+    let key = rustls_pemfile::private_key(key_file)?
+        .ok_or(anyhow!("No private key in [{}]", key_path.display()))?;
+    CertifiedKey::from_der(cert_chain, key, provider).map_err(|err| {
+        anyhow!(
+            "Invalid certificate [{}] or key [{}]: {}",
+            cert_path.display(),
+            key_path.display(),
+            err
+        )
+    })
+}
+
+/// The protocol settings of the TLS server.
+pub struct TlsPolicy {
+    pub min_version: TlsVersion,
+    pub cipher_suites: Vec<String>,
+    pub session_tickets: bool,
+    pub session_cache: usize,
+}
+
+impl From<&Config> for TlsPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            min_version: config.tls_min_version,
+            cipher_suites: config.tls_cipher_suites.clone(),
+            session_tickets: config.tls_session_tickets,
+            session_cache: config.tls_session_cache,
+        }
+    }
+}
+
+/// The default crypto provider, restricted to the cipher suites named by `names`.
+pub(crate) fn provider_with_suites(names: &[String]) -> Result<CryptoProvider> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if names.is_empty() {
+        return Ok(provider);
+    }
+    let available = provider
+        .cipher_suites
+        .iter()
+        .filter_map(|suite| suite.suite().as_str())
+        .collect::<Vec<_>>();
+    if let Some(unknown) = names
+        .iter()
+        .find(|name| !available.contains(&name.as_str()))
+    {
+        return Err(anyhow!(
+            "Unknown cipher suite [{}], supported are: {}",
+            unknown,
+            available.join(", ")
+        ));
+    }
+    provider.cipher_suites.retain(|suite| {
+        suite
+            .suite()
+            .as_str()
+            .is_some_and(|name| names.iter().any(|n| n == name))
+    });
+    Ok(provider)
+}
+
+/// The CA certificates client certificates are verified against.
+pub(crate) fn client_roots(ca_path: &Path) -> Result<RootCertStore> {
+    let ca_file = &mut BufReader::new(
+        std::fs::File::open(ca_path)
+            .map_err(|err| anyhow!("Reading [{}] failed: {}", ca_path.display(), err))?,
+    );
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(ca_file) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("No CA certificate in [{}]", ca_path.display()));
+    }
+    Ok(roots)
+}
+
+/// The TLS server config. ALPN is set by the listeners serving it, following `tls_alpn`.
+pub fn tls_config(
+    resolver: Arc<CertResolver>,
+    client_ca: Option<(&Path, ClientAuth)>,
+    policy: &TlsPolicy,
+) -> Result<ServerConfig> {
+    let provider = Arc::new(provider_with_suites(&policy.cipher_suites)?);
+    let versions: &[&rustls::SupportedProtocolVersion] = match policy.min_version {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)
+        .map_err(|err| anyhow!("No usable cipher suite for the TLS versions: {}", err))?;
+    let builder = match client_ca {
+        Some((ca_path, client_auth)) => {
+            let roots = client_roots(ca_path)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match client_auth {
+                ClientAuth::Required => verifier.build()?,
+                ClientAuth::Optional | ClientAuth::CertOrPassword => {
+                    verifier.allow_unauthenticated().build()?
+                }
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(resolver);
+    config.session_storage = if policy.session_cache > 0 {
+        ServerSessionMemoryCache::new(policy.session_cache)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+    if policy.session_tickets {
+        config.ticketer = rustls::crypto::aws_lc_rs::Ticketer::new()?;
+    }
+    Ok(config)
+}
+
+/// The user name in the `field` of a DER encoded certificate.
+pub fn cert_user(der: &[u8], field: CertUserField) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let user = match field {
+        CertUserField::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok()),
+        CertUserField::Email | CertUserField::DnsName => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match (name, field) {
+                (GeneralName::RFC822Name(email), CertUserField::Email) => Some(*email),
+                (GeneralName::DNSName(dns), CertUserField::DnsName) => Some(*dns),
+                _ => None,
+            }),
+    };
+    user.filter(|user| !user.is_empty()).map(String::from)
+}
+
+/// The port requests of the plain HTTP listener are redirected to.
+pub struct HttpsPort(pub u16);
+
+/// The value of the `Strict-Transport-Security` header, `None` when HSTS is disabled.
+pub struct Hsts(pub Option<HeaderValue>);
+
+/// Adds the [`Hsts`] header to the responses to requests over HTTPS. The same app serves the
+/// plain listeners, over which RFC 6797 forbids sending it.
+pub async fn add_hsts<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let hsts = req
+        .app_data::<web::Data<Hsts>>()
+        .and_then(|hsts| hsts.0.clone())
+        .filter(|_| is_https(req.request()));
+    let mut res = next.call(req).await?;
+    if let Some(hsts) = hsts
+        && !res.headers().contains_key(STRICT_TRANSPORT_SECURITY)
+    {
+        res.headers_mut().insert(STRICT_TRANSPORT_SECURITY, hsts);
+    }
+    Ok(res)
+}
+
+/// Records [`TlsConnection`], and the [`ClientCert`] of TLS connections whose client presented a
+/// certificate or [`UnknownCertUser`] when it names no user. It has already been verified against
+/// the client CA during the handshake.
+pub fn on_connect(field: CertUserField) -> impl Fn(&dyn Any, &mut Extensions) + Send + Sync {
+    move |conn, data| {
+        let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+            return;
+        };
+        data.insert(TlsConnection);
+        let (_, session) = stream.get_ref();
+        let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) else {
+            return;
+        };
+        match cert_user(cert, field) {
+            Some(user) => {
+                data.insert(ClientCert { user });
+            }
+            None => {
+                eprintln!("No {:?} in the client certificate", field);
+                data.insert(UnknownCertUser);
+            }
+        }
+    }
+}
+
+/// The HTTPS URL of `path_and_query` on `host`, whose port is replaced by `port`.
+fn https_location(host: &str, port: u16, path_and_query: &str) -> Option<String> {
+    let host = host.parse::<Uri>().ok()?;
+    let host = host.host()?;
+    if port == 443 {
+        Some(format!("https://{}{}", host, path_and_query))
+    } else {
+        Some(format!("https://{}:{}{}", host, port, path_and_query))
+    }
+}
+
+/// Answers every request of the plain HTTP listener with a redirect to the same path and query
+/// over HTTPS, permanent for `GET` and `HEAD` and method preserving otherwise. It is the only
+/// handler of the app serving that listener, so nothing else can be reached over plain HTTP.
+pub async fn redirect_plain(https_port: web::Data<HttpsPort>, req: HttpRequest) -> HttpResponse {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| https_location(host, https_port.0, path_and_query));
+    let Some(location) = location else {
+        return HttpResponse::BadRequest().body("Missing Host header");
+    };
+    let mut resp = if req.method() == Method::GET || req.method() == Method::HEAD {
+        HttpResponse::MovedPermanently()
+    } else {
+        HttpResponse::PermanentRedirect()
+    };
+    resp.insert_header((LOCATION, location)).finish()
+}
+
+/// Refuses to overwrite `path` unless `force` is set.
+fn check_new(path: &Path, force: bool) -> Result<()> {
+    if !force && path.exists() {
+        return Err(anyhow!(
+            "[{}] already exists, use `--force` to overwrite it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Writes `content` to `path` with permissions `mode` through a temporary file renamed over
+/// it, so an existing file is replaced along with its permissions.
+fn write_new(path: &Path, content: &str, mode: u32) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&tmp_path)?;
+        // the mode only applies to a created file, not to one left by an earlier run
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        file.write_all(content.as_bytes())?;
+        std::fs::rename(&tmp_path, path)
+    };
+    write().map_err(|err| {
+        let _ = std::fs::remove_file(&tmp_path);
+        anyhow!("Cannot write [{}]: {}", path.display(), err)
+    })
+}
+
+/// Generates the certificate and key of the `gen-cert` subcommand, and prints the config
+/// snippet using them with the certificate fingerprint.
+pub fn run_gen_cert(args: &GenCertArgs) -> Result<()> {
+    let mut params = CertificateParams::new(args.sans.clone())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, args.sans[0].as_str());
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now
+        .checked_add(time::Duration::days(args.days.into()))
+        .ok_or_else(|| anyhow!("--days {} is too far in the future", args.days))?;
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    // both are checked before anything is written, so a failure leaves no half of a pair
+    check_new(&args.cert_out, args.force)?;
+    check_new(&args.key_out, args.force)?;
+    let key = KeyPair::generate()?;
+    let cert = match (&args.ca_cert, &args.ca_key) {
+        (Some(ca_cert), Some(ca_key)) => {
+            let ca_key = KeyPair::from_pem(&std::fs::read_to_string(ca_key)?)?;
+            let issuer = Issuer::from_ca_cert_pem(&std::fs::read_to_string(ca_cert)?, ca_key)?;
+            params.use_authority_key_identifier_extension = true;
+            params.signed_by(&key, &issuer)?
+        }
+        _ => params.self_signed(&key)?,
+    };
+
+    write_new(&args.key_out, &key.serialize_pem(), 0o600)?;
+    write_new(&args.cert_out, &cert.pem(), 0o644)?;
+    let fingerprint = Sha256::digest(cert.der())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":");
+    println!("SHA-256 fingerprint: {}", fingerprint);
+    println!("cert_path = {:?}", args.cert_out.display().to_string());
+    println!("key_path = {:?}", args.key_out.display().to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, HttpResponse};
+    use rustls::pki_types::CertificateDer;
+
+    #[test]
+    fn test_cert_user() {
+        let pem = std::fs::read("res_dir/client_cert.pem").unwrap();
+        let der = rustls_pemfile::certs(&mut pem.as_slice())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cert_user(&der, CertUserField::CommonName).as_deref(),
+            Some("device1")
+        );
+        assert_eq!(
+            cert_user(&der, CertUserField::Email).as_deref(),
+            Some("device1@example.com")
+        );
+        assert_eq!(cert_user(&der, CertUserField::DnsName), None);
+    }
+
+    fn first_cert(path: &Path) -> CertificateDer<'static> {
+        let pem = std::fs::read(path).unwrap();
+        let der = rustls_pemfile::certs(&mut pem.as_slice()).next();
+        der.unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let cert = Path::new("res_dir/cert.pem");
+        let key = Path::new("res_dir/key.pem");
+        let resolver = Arc::new(CertResolver::new(cert, key, &[]).unwrap());
+        let policy = TlsPolicy {
+            min_version: TlsVersion::Tls12,
+            cipher_suites: Vec::new(),
+            session_tickets: false,
+            session_cache: 256,
+        };
+        let config = tls_config(resolver.clone(), None, &policy).unwrap();
+        assert!(!config.ticketer.enabled());
+        let ca = Path::new("res_dir/client_ca.pem");
+        for client_auth in [ClientAuth::Optional, ClientAuth::Required] {
+            assert!(tls_config(resolver.clone(), Some((ca, client_auth)), &policy).is_ok());
+        }
+        let no_ca = Some((Path::new("res_dir/none.pem"), ClientAuth::Required));
+        assert!(tls_config(resolver.clone(), no_ca, &policy).is_err());
+
+        let mut policy = TlsPolicy {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec![String::from("TLS13_AES_256_GCM_SHA384")],
+            session_tickets: true,
+            session_cache: 0,
+        };
+        let config = tls_config(resolver.clone(), None, &policy).unwrap();
+        assert!(config.ticketer.enabled());
+        assert_eq!(config.crypto_provider().cipher_suites.len(), 1);
+        // no TLS 1.3 suite left
+        policy.cipher_suites = vec![String::from("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384")];
+        assert!(tls_config(resolver.clone(), None, &policy).is_err());
+        policy.cipher_suites = vec![String::from("TLS_RSA_WITH_RC4_128_MD5")];
+        assert!(tls_config(resolver.clone(), None, &policy).is_err());
+
+        let mut vhost = VhostEntry {
+            hosts: vec![String::from("books.localhost")],
+            cert_path: Some(PathBuf::from("res_dir/vhost_cert.pem")),
+            key_path: Some(PathBuf::from("res_dir/vhost_key.pem")),
+            root_dir: PathBuf::from("res_dir"),
+            users: None,
+            users_file: None,
+            groups: None,
+            acl: None,
+        };
+        let resolver = CertResolver::new(cert, key, &[vhost.clone()]).unwrap();
+        let vhost_cert = first_cert(Path::new("res_dir/vhost_cert.pem"));
+        assert_eq!(resolver.lookup(Some("Books.localhost")).cert[0], vhost_cert);
+        assert_eq!(resolver.lookup(Some("other")).cert[0], first_cert(cert));
+        assert_eq!(resolver.lookup(None).cert[0], first_cert(cert));
+        // the key does not belong to the certificate
+        vhost.key_path = Some(PathBuf::from("res_dir/key.pem"));
+        assert!(CertResolver::new(cert, key, &[vhost]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_hsts() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Hsts(Some(HeaderValue::from_static(
+                    "max-age=60",
+                )))))
+                .default_service(web::to(HttpResponse::Ok))
+                .wrap(from_fn(add_hsts)),
+        )
+        .await;
+        // never over plain HTTP, only over HTTPS, here to a proxy in front of the server
+        let resp = call_service(&app, TestRequest::default().to_request()).await;
+        assert!(!resp.headers().contains_key(STRICT_TRANSPORT_SECURITY));
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=60"
+        );
+    }
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("books.lan:8080", 8443, "/a%20b/?sort=size").as_deref(),
+            Some("https://books.lan:8443/a%20b/?sort=size")
+        );
+        assert_eq!(
+            https_location("[::1]", 443, "/").as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(https_location("bad host", 443, "/"), None);
+    }
+
+    #[test]
+    fn test_cert_reload() {
+        let dir = TempDir::new("certs");
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::copy("res_dir/cert.pem", &cert).unwrap();
+        std::fs::copy("res_dir/key.pem", &key).unwrap();
+        let resolver = CertResolver::new(&cert, &key, &[]).unwrap();
+        let old_cert = first_cert(&cert);
+
+        // a renewal half done keeps the previous pair
+        std::fs::copy("res_dir/vhost_cert.pem", &cert).unwrap();
+        assert_eq!(resolver.reload(), (1, 1));
+        assert_eq!(resolver.lookup(None).cert[0], old_cert);
+        std::fs::write(&key, "broken").unwrap();
+        assert_eq!(resolver.reload(), (1, 1));
+        assert_eq!(resolver.lookup(None).cert[0], old_cert);
+
+        std::fs::copy("res_dir/vhost_key.pem", &key).unwrap();
+        assert_eq!(resolver.reload(), (0, 1));
+        assert_eq!(resolver.lookup(None).cert[0], first_cert(&cert));
+
+        // a reload loading while the config is reloaded does not undo the replaced pairs
+        let loaded = resolver.load_again();
+        let default_cert = Path::new("res_dir/cert.pem");
+        resolver
+            .replace(default_cert, Path::new("res_dir/key.pem"), &[])
+            .unwrap();
+        assert_eq!(resolver.swap_in(loaded), (0, 1));
+        assert_eq!(resolver.lookup(None).cert[0], first_cert(default_cert));
+        resolver.replace(&cert, &key, &[]).unwrap();
+
+        // a reloaded config replaces all pairs, or none when one fails
+        let bad_key = Path::new("res_dir/key.pem");
+        assert!(resolver.replace(&cert, bad_key, &[]).is_err());
+        assert_eq!(resolver.lookup(None).cert[0], first_cert(&cert));
+        resolver.replace(default_cert, bad_key, &[]).unwrap();
+        assert_eq!(resolver.lookup(None).cert[0], first_cert(default_cert));
+    }
+
+    #[test]
+    fn test_gen_cert() {
+        let dir = TempDir::new("gen");
+        let mut args = GenCertArgs {
+            sans: vec![String::from("books.localhost"), String::from("192.0.2.1")],
+            days: 30,
+            ca_cert: None,
+            ca_key: None,
+            cert_out: dir.join("cert.pem"),
+            key_out: dir.join("key.pem"),
+            force: false,
+        };
+        run_gen_cert(&args).unwrap();
+        // the pair is usable by the server
+        assert!(CertResolver::new(&args.cert_out, &args.key_out, &[]).is_ok());
+        let der = first_cert(&args.cert_out);
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        let sans = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(sans.value.general_names.len(), 2);
+        let validity = cert.validity().time_to_expiration().unwrap();
+        assert!(validity.whole_days() >= 29 && validity.whole_days() <= 30);
+        // existing files are kept unless forced
+        assert!(run_gen_cert(&args).is_err());
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&args.key_out), 0o600);
+        // nothing is written when only one of the outputs exists
+        std::fs::remove_file(&args.cert_out).unwrap();
+        assert!(run_gen_cert(&args).is_err());
+        assert!(!args.cert_out.exists());
+        // a forced key replaces a readable one with the permissions of a key
+        std::fs::set_permissions(&args.key_out, std::fs::Permissions::from_mode(0o644)).unwrap();
+        args.force = true;
+        run_gen_cert(&args).unwrap();
+        assert_eq!(mode(&args.key_out), 0o600);
+        assert_eq!(mode(&args.cert_out), 0o644);
+        args.days = u32::MAX;
+        assert!(run_gen_cert(&args).is_err());
+        args.days = 30;
+
+        // signed by a local CA
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Local CA");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca_cert.pem()).unwrap();
+        std::fs::write(dir.join("ca_key.pem"), ca_key.serialize_pem()).unwrap();
+        args.ca_cert = Some(dir.join("ca.pem"));
+        args.ca_key = Some(dir.join("ca_key.pem"));
+        run_gen_cert(&args).unwrap();
+        let der = first_cert(&args.cert_out);
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        assert_eq!(cert.issuer().to_string(), "CN=Local CA");
+    }
+}
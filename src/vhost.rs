@@ -0,0 +1,225 @@
+use crate::AppState;
+use crate::auth::AuthInfo;
+use crate::config::{Config, VhostEntry};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::Uri;
+use actix_web::http::header::HOST;
+use actix_web::middleware::Next;
+use actix_web::web;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+/// What a site is served with, the top level one or a vhost.
+#[derive(Clone)]
+pub struct Site {
+    pub app_state: web::Data<AppState>,
+    /// Only set when authentication is enabled for the site.
+    pub auth_info: Option<AuthInfo>,
+}
+
+/// The sites by host name.
+pub struct Vhosts {
+    default: Site,
+    by_host: HashMap<String, Site>,
+}
+
+impl Vhosts {
+    pub fn new(default: Site) -> Self {
+        Self {
+            default,
+            by_host: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, hosts: &[String], site: Site) {
+        for host in hosts {
+            self.by_host.insert(host.to_ascii_lowercase(), site.clone());
+        }
+    }
+
+    pub fn default_site(&self) -> &Site {
+        &self.default
+    }
+
+    /// The vhost site serving `host`, if any.
+    pub fn get(&self, host: &str) -> Option<&Site> {
+        self.by_host.get(&host.to_ascii_lowercase())
+    }
+
+    fn site(&self, host: Option<&str>) -> &Site {
+        host.and_then(|host| self.get(host))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// The sites being served, replaced as a whole when the config is reloaded. Requests keep the
+/// sites they started with.
+pub struct SiteTable(RwLock<Arc<Vhosts>>);
+
+impl SiteTable {
+    pub fn new(vhosts: Vhosts) -> Self {
+        Self(RwLock::new(Arc::new(vhosts)))
+    }
+
+    pub fn current(&self) -> Arc<Vhosts> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, vhosts: Vhosts) {
+        *self.0.write().unwrap() = Arc::new(vhosts);
+    }
+}
+
+/// The config of a vhost site: the top level config with the settings of the vhost.
+pub fn vhost_config(config: &Config, vhost: &VhostEntry) -> Config {
+    let mut config = config.clone();
+    config.root_dir = vhost.root_dir.clone();
+    config.cert_path = vhost.cert_path.clone().or(config.cert_path);
+    config.key_path = vhost.key_path.clone().or(config.key_path);
+    if vhost.users.is_some() || vhost.users_file.is_some() {
+        config.user_name = None;
+        config.password_hash = None;
+        config.users = vhost.users.clone().unwrap_or_default();
+        config.users_file = vhost.users_file.clone();
+    }
+    if let Some(groups) = &vhost.groups {
+        config.groups = groups.clone();
+    }
+    if let Some(acl) = &vhost.acl {
+        config.acl = acl.clone();
+    }
+    config.vhosts = Vec::new();
+    config
+}
+
+/// The host name the request is for, without the port.
+fn request_host(req: &ServiceRequest) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(String::from(host));
+    }
+    let host = req.headers().get(HOST)?.to_str().ok()?;
+    let uri = host.parse::<Uri>().ok()?;
+    uri.host().map(String::from)
+}
+
+/// Makes the state of the site the `Host` header names available to the handlers and the
+/// authentication middleware, which therefore has to be wrapped inside this one.
+pub async fn select<B: MessageBody>(
+    sites: web::Data<SiteTable>,
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let vhosts = sites.current();
+    let site = vhosts.site(request_host(&req).as_deref());
+    let mut data = Extensions::new();
+    data.insert(site.app_state.clone());
+    if let Some(auth_info) = &site.auth_info {
+        data.insert(auth_info.clone());
+    }
+    req.add_data_container(Rc::new(data));
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::share::{ShareStore, share_url};
+    use crate::{app_config, auth};
+    use actix_http::StatusCode;
+    use actix_web::{App, middleware::from_fn, test};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_vhosts() {
+        let users = HashMap::from([(String::from("alice"), bcrypt::hash("alicepass", 4).unwrap())]);
+        let mut vhosts = Vhosts::new(Site {
+            app_state: web::Data::new(AppState::new(PathBuf::from("."))),
+            auth_info: Some(AuthInfo::new(users)),
+        });
+        vhosts.add(
+            &[String::from("Books.Example")],
+            Site {
+                app_state: web::Data::new(AppState::new(PathBuf::from("res_dir"))),
+                auth_info: None,
+            },
+        );
+        let app = test::init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(web::Data::new(SiteTable::new(vhosts)))
+                .wrap(from_fn(auth::authenticate))
+                .wrap(from_fn(select)),
+        )
+        .await;
+        for (host, uri, status) in [
+            ("books.example", "/v2.epub", StatusCode::OK),
+            ("BOOKS.example:1131", "/v2.epub", StatusCode::OK),
+            ("books.example", "/Cargo.toml", StatusCode::NOT_FOUND),
+            ("other.example", "/Cargo.toml", StatusCode::UNAUTHORIZED),
+            ("other.example", "/v2.epub", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = test::TestRequest::default()
+                .uri(uri)
+                .insert_header((HOST, host))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{} {}", host, uri);
+        }
+        let req = test::TestRequest::default().uri("/Cargo.toml").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_vhost_shares() {
+        let site = |root: &str, hosts: &[String]| {
+            let users =
+                HashMap::from([(String::from("alice"), bcrypt::hash("alicepass", 4).unwrap())]);
+            let mut app_state = AppState::new(PathBuf::from(root));
+            app_state.shares = Arc::new(ShareStore::new(Some("secret"), hosts));
+            Site {
+                app_state: web::Data::new(app_state),
+                auth_info: Some(AuthInfo::new(users)),
+            }
+        };
+        let books = [String::from("books.example")];
+        let mut vhosts = Vhosts::new(site(".", &[]));
+        vhosts.add(&books, site("res_dir", &books));
+        let mint = |site: &Site, path: &str| {
+            let (token, _) = site
+                .app_state
+                .shares
+                .mint(path, Duration::from_secs(60), None, None)
+                .unwrap();
+            share_url(path, &token)
+        };
+        let top_link = mint(vhosts.default_site(), "/");
+        let books_link = mint(vhosts.get("books.example").unwrap(), "/");
+        let app = test::init_service(
+            App::new()
+                .configure(app_config)
+                .app_data(web::Data::new(SiteTable::new(vhosts)))
+                .wrap(from_fn(auth::authenticate))
+                .wrap(from_fn(select)),
+        )
+        .await;
+        // the same secret signs both sites, a link of one grants nothing on the other
+        for (host, link, status) in [
+            ("other.example", &top_link, StatusCode::OK),
+            ("books.example", &top_link, StatusCode::FORBIDDEN),
+            ("books.example", &books_link, StatusCode::OK),
+            ("other.example", &books_link, StatusCode::FORBIDDEN),
+        ] {
+            let req = test::TestRequest::default()
+                .uri(link)
+                .insert_header((HOST, host))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{} {}", host, link);
+        }
+    }
+}
@@ -26,6 +26,346 @@ async fn test_https() {
         .await
         .unwrap();
     assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    for (user, password, status) in [
+        ("guest", "guestpass", reqwest::StatusCode::OK),
+        ("reader", "readerpass", reqwest::StatusCode::OK),
+        ("reader", "guestpass", reqwest::StatusCode::UNAUTHORIZED),
+    ] {
+        let resp = client
+            .get("https://127.0.0.1:11310")
+            .basic_auth(user, Some(password))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status, "{}", user);
+    }
+    // public by the ACL of the sample config
+    let resp = client
+        .get("https://127.0.0.1:11310/images/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp = client.get("https://127.0.0.1:11310").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers()[reqwest::header::STRICT_TRANSPORT_SECURITY],
+        "max-age=31536000"
+    );
+
+    // the plain listener only redirects to HTTPS
+    let plain = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    for (method, status) in [
+        (reqwest::Method::GET, reqwest::StatusCode::MOVED_PERMANENTLY),
+        (
+            reqwest::Method::POST,
+            reqwest::StatusCode::PERMANENT_REDIRECT,
+        ),
+    ] {
+        let resp = plain
+            .request(method, "http://127.0.0.1:11311/images/?sort=size")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status);
+        assert_eq!(
+            resp.headers()[reqwest::header::LOCATION],
+            "https://127.0.0.1:11310/images/?sort=size"
+        );
+    }
+
+    // the client certificate signs in as its common name
+    let cert = std::fs::read("res_dir/client_cert.pem").unwrap();
+    let key = std::fs::read("res_dir/client_key.pem").unwrap();
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(reqwest::Identity::from_pkcs8_pem(&cert, &key).unwrap())
+        .build()
+        .unwrap();
+    for (path, status) in [
+        ("/", reqwest::StatusCode::OK),
+        ("/v2.epub", reqwest::StatusCode::FORBIDDEN),
+    ] {
+        let resp = client
+            .get(format!("https://127.0.0.1:11310{}", path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status, "{}", path);
+    }
+
+    // the vhost is selected by SNI for the certificate and by the Host header for the contents
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .resolve("books.localhost", "127.0.0.1:11310".parse().unwrap())
+        .build()
+        .unwrap();
+    let resp = client
+        .get("https://books.localhost:11310/dir_list.png")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let tls_info = resp.extensions().get::<reqwest::tls::TlsInfo>().unwrap();
+    let vhost_cert = std::fs::read("res_dir/vhost_cert.pem").unwrap();
+    let vhost_cert = rustls_pemfile::certs(&mut vhost_cert.as_slice())
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(tls_info.peer_certificate(), Some(vhost_cert.as_ref()));
+
+    server_handle.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_listen() {
+    use std::io::{Read, Write};
+
+    let socket = std::env::temp_dir().join(format!("my_cont_srv_{}.sock", std::process::id()));
+    let listen = format!(
+        "[{{ address = \"127.0.0.1\", port = 11320 }}, \
+         {{ address = \"127.0.0.1\", port = 11321, tls = false }}, \
+         {{ path = \"{}\", mode = 0o600 }}]",
+        socket.display()
+    );
+    let cli = parse_cli_from(args_to_vec(&[
+        "my-cont-srv",
+        "-c",
+        "res_dir/config.toml",
+        "--listen",
+        &listen,
+        "--http-redirect-port=11322",
+    ]));
+    let cfg = get_config(cli).unwrap();
+    let server = create_server(cfg).await.unwrap();
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    for (url, status) in [
+        ("https://127.0.0.1:11320/images/", reqwest::StatusCode::OK),
+        // a plain listener serves the contents rather than redirecting
+        ("http://127.0.0.1:11321/images/", reqwest::StatusCode::OK),
+        (
+            "http://127.0.0.1:11322/images/",
+            reqwest::StatusCode::MOVED_PERMANENTLY,
+        ),
+    ] {
+        let resp = client.get(url).send().await.unwrap();
+        assert_eq!(resp.status(), status, "{}", url);
+    }
+
+    let mode =
+        std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&socket).unwrap().permissions());
+    assert_eq!(mode & 0o777, 0o600);
+    let response = tokio::task::spawn_blocking(move || {
+        let mut stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+        stream
+            .write_all(b"GET /images/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        std::fs::remove_file(&socket).unwrap();
+        response
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
 
     server_handle.stop(true).await;
 }
+
+#[tokio::test]
+async fn test_cert_without_user() {
+    let cli = parse_cli_from(args_to_vec(&[
+        "my-cont-srv",
+        "-c",
+        "res_dir/config.toml",
+        "--port=11330",
+        "--http-redirect-port=11331",
+        "--client-cert-user=dns_name",
+    ]));
+    let cfg = get_config(cli).unwrap();
+    let server = create_server(cfg).await.unwrap();
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    // the sample client certificate has no DNS name, so it is refused rather than anonymous
+    let cert = std::fs::read("res_dir/client_cert.pem").unwrap();
+    let key = std::fs::read("res_dir/client_key.pem").unwrap();
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(reqwest::Identity::from_pkcs8_pem(&cert, &key).unwrap())
+        .build()
+        .unwrap();
+    let resp = client
+        .get("https://127.0.0.1:11330/images/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    // refused by a middleware, yet with the header of every HTTPS response
+    assert_eq!(
+        resp.headers()[reqwest::header::STRICT_TRANSPORT_SECURITY],
+        "max-age=31536000"
+    );
+
+    server_handle.stop(true).await;
+}
+
+/// Accepts any server certificate, the ALPN test only looks at the negotiated protocol.
+#[derive(Debug)]
+struct AnyServerCert;
+
+impl rustls::client::danger::ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::pki_types::CertificateDer,
+        _: &[rustls::pki_types::CertificateDer],
+        _: &rustls::pki_types::ServerName,
+        _: &[u8],
+        _: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _: &[u8],
+        _: &rustls::pki_types::CertificateDer,
+        _: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _: &[u8],
+        _: &rustls::pki_types::CertificateDer,
+        _: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The protocol the server on `port` picks from the ALPN `protocols` offered by the client.
+fn negotiate(port: u16, protocols: &[&[u8]]) -> std::io::Result<Option<Vec<u8>>> {
+    let mut config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(std::sync::Arc::new(AnyServerCert))
+        .with_no_client_auth();
+    config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    let server_name = "localhost".try_into().unwrap();
+    let mut conn = rustls::ClientConnection::new(std::sync::Arc::new(config), server_name).unwrap();
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(conn.alpn_protocol().map(<[u8]>::to_vec))
+}
+
+#[tokio::test]
+async fn test_alpn() {
+    let cli = parse_cli_from(args_to_vec(&[
+        "my-cont-srv",
+        "-c",
+        "res_dir/config.toml",
+        "--port=11340",
+        "--http-redirect-port=11341",
+        "--tls-alpn",
+        r#"["http/1.1"]"#,
+    ]));
+    let cfg = get_config(cli).unwrap();
+    let server = create_server(cfg).await.unwrap();
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    let protocols = tokio::task::spawn_blocking(|| {
+        (
+            negotiate(11340, &[b"h2", b"http/1.1"]).unwrap(),
+            negotiate(11340, &[b"h2"]),
+        )
+    })
+    .await
+    .unwrap();
+    assert_eq!(protocols.0.as_deref(), Some(&b"http/1.1"[..]));
+    assert!(protocols.1.is_err());
+
+    // served like the other HTTPS listeners: HSTS on every response and secure cookies
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let resp = client.get("https://127.0.0.1:11340").send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers()[reqwest::header::STRICT_TRANSPORT_SECURITY],
+        "max-age=31536000"
+    );
+    let resp = client
+        .post("https://127.0.0.1:11340/login")
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .body("user=guest&password=guestpass")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::SEE_OTHER);
+    let cookie = resp.headers()[reqwest::header::SET_COOKIE]
+        .to_str()
+        .unwrap();
+    assert!(cookie.contains("Secure"), "{}", cookie);
+
+    server_handle.stop(true).await;
+}
+
+#[test]
+fn test_print_config() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_my_cont_srv"))
+        .args([
+            "-c",
+            "res_dir/config.toml",
+            "--workers",
+            "4",
+            "--print-config",
+        ])
+        .env("MY_CONT_SRV_WORKERS", "5")
+        .env("MY_CONT_SRV_PORT", "1139")
+        .env("MY_CONT_SRV_SHARE_SECRET", "secret")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    for line in [
+        "workers = 4  # command line --workers",
+        "port = 1139  # environment variable MY_CONT_SRV_PORT",
+        "address = \"127.0.0.1\"  # config file res_dir/config.toml",
+        "share_secret = \"<redacted>\"  # environment variable MY_CONT_SRV_SHARE_SECRET",
+    ] {
+        assert!(output.lines().any(|l| l == line), "{}", line);
+    }
+    // password hashes are redacted as well, including those of the `users` entries
+    assert!(!output.contains("$2b$"));
+    assert!(
+        output
+            .lines()
+            .any(|l| l.starts_with("password_hash = \"<redacted>\""))
+    );
+}